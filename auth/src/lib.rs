@@ -0,0 +1,102 @@
+//! Issues netcode connect tokens signed with the server's private key. The token's user data
+//! binds the connection to a `PlayerId`, so clients cannot pick their own.
+use std::{
+    fs,
+    io::{self, Read, Write},
+    net::SocketAddr,
+    path::Path,
+    time::SystemTime,
+};
+
+use bevy_renet::renet::transport::{
+    generate_random_bytes, ConnectToken, TokenGenerationError, NETCODE_KEY_BYTES,
+    NETCODE_USER_DATA_BYTES,
+};
+use common::{protocol::PROTOCOL_VERSION, PlayerId};
+
+pub type PrivateKey = [u8; NETCODE_KEY_BYTES];
+
+/// How long an issued token can be used to connect.
+pub const TOKEN_EXPIRE_SECONDS: u64 = 300;
+/// Seconds without packets before a connection made with an issued token times out.
+pub const TOKEN_TIMEOUT_SECONDS: i32 = 15;
+
+pub fn generate_private_key() -> PrivateKey {
+    generate_random_bytes()
+}
+
+pub fn read_private_key(path: &Path) -> io::Result<PrivateKey> {
+    fs::read(path)?.try_into().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("private key must be {} bytes", NETCODE_KEY_BYTES),
+        )
+    })
+}
+
+pub fn write_private_key(path: &Path, private_key: &PrivateKey) -> io::Result<()> {
+    fs::write(path, private_key)
+}
+
+pub fn player_id_to_user_data(player_id: PlayerId) -> [u8; NETCODE_USER_DATA_BYTES] {
+    let mut user_data = [0; NETCODE_USER_DATA_BYTES];
+    user_data[..8].copy_from_slice(&player_id.0.to_le_bytes());
+    user_data
+}
+
+pub fn player_id_from_user_data(user_data: &[u8; NETCODE_USER_DATA_BYTES]) -> PlayerId {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&user_data[..8]);
+    PlayerId(u64::from_le_bytes(bytes))
+}
+
+/// A connect token along with the player it was issued for. The player id is also stored in
+/// the token's encrypted user data, which is what the server trusts.
+pub struct IssuedToken {
+    pub player_id: PlayerId,
+    pub connect_token: ConnectToken,
+}
+
+impl IssuedToken {
+    pub fn issue(
+        private_key: &PrivateKey,
+        player_id: PlayerId,
+        server_addresses: Vec<SocketAddr>,
+    ) -> Result<Self, TokenGenerationError> {
+        let current_time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap();
+        // Netcode client ids only need to be unique between connections.
+        let client_id = u64::from_le_bytes(generate_random_bytes());
+        let connect_token = ConnectToken::generate(
+            current_time,
+            PROTOCOL_VERSION,
+            TOKEN_EXPIRE_SECONDS,
+            client_id,
+            TOKEN_TIMEOUT_SECONDS,
+            server_addresses,
+            Some(&player_id_to_user_data(player_id)),
+            private_key,
+        )?;
+        Ok(Self {
+            player_id,
+            connect_token,
+        })
+    }
+
+    pub fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        writer.write_all(&self.player_id.0.to_le_bytes())?;
+        self.connect_token.write(writer)
+    }
+
+    pub fn read(reader: &mut impl Read) -> io::Result<Self> {
+        let mut player_id = [0; 8];
+        reader.read_exact(&mut player_id)?;
+        let connect_token = ConnectToken::read(reader)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        Ok(Self {
+            player_id: PlayerId(u64::from_le_bytes(player_id)),
+            connect_token,
+        })
+    }
+}
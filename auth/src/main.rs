@@ -0,0 +1,63 @@
+use std::{fs::File, net::SocketAddr, path::PathBuf};
+
+use auth::IssuedToken;
+use clap::{Parser, Subcommand};
+use common::PlayerId;
+
+#[derive(Parser, Debug)]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Generates a new private key shared by the server and this tool.
+    Keygen {
+        #[arg(long, default_value = "private.key")]
+        out: PathBuf,
+    },
+    /// Issues a connect token for a player.
+    Token {
+        #[arg(long)]
+        player_id: u64,
+
+        #[arg(long, default_value = "private.key")]
+        key: PathBuf,
+
+        #[arg(long, default_value = "127.0.0.1:5000")]
+        server: SocketAddr,
+
+        #[arg(long, default_value = "token.bin")]
+        out: PathBuf,
+    },
+}
+
+fn main() {
+    let args = Args::parse();
+
+    match args.command {
+        Command::Keygen { out } => {
+            auth::write_private_key(&out, &auth::generate_private_key())
+                .unwrap_or_else(|err| panic!("Failed to write {}: {}", out.display(), err));
+            println!("Wrote private key to {}", out.display());
+        }
+        Command::Token {
+            player_id,
+            key,
+            server,
+            out,
+        } => {
+            let private_key = auth::read_private_key(&key)
+                .unwrap_or_else(|err| panic!("Failed to read {}: {}", key.display(), err));
+            let token = IssuedToken::issue(&private_key, PlayerId(player_id), vec![server])
+                .unwrap_or_else(|err| panic!("Failed to issue token: {}", err));
+            let mut file = File::create(&out)
+                .unwrap_or_else(|err| panic!("Failed to create {}: {}", out.display(), err));
+            token
+                .write(&mut file)
+                .unwrap_or_else(|err| panic!("Failed to write {}: {}", out.display(), err));
+            println!("Wrote token for player {} to {}", player_id, out.display());
+        }
+    }
+}
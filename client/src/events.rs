@@ -1,98 +1,271 @@
 use bevy::prelude::*;
-use bevy_renet::renet::{DefaultChannel, RenetClient};
+use bevy_renet::renet::{
+    transport::{NetcodeClientTransport, NetcodeDisconnectReason},
+    RenetClient,
+};
 use common::{
+    channel::NetClient,
+    delta::{GameSyncDelta, SyncHistory},
+    message::FromServer,
+    protocol::PROTOCOL_VERSION,
+    replication::Replicated,
     rollback::{
         ComponentRollbacks, GameSyncRequest, InputRollback, RollbackRequest, SyncFrameCount,
     },
     schedule::ClientState,
-    Player, PlayerLogin, ROMFromClient, ROMFromServer, ServerEntityMap, ServerObject, UMFromServer,
+    GameJoined, GameSync, GameSyncAck, LoginAccepted, LoginRejected, PlayerLogin, ROMFromClient,
+    ServerEntityMap, ShutdownNotice, UMFromClient,
+};
+
+use crate::{
+    input::{InputAccumulator, UnackedInputs},
+    messages::ServerMessages,
+    reconnect::Reconnect,
+    ui::{chat::ChatBox, menu::MenuStatus},
+    LocalPlayer,
 };
 
-use crate::{messages::ServerMessages, spawn::get_player_sprite, LocalPlayer};
+/// Seconds until the server shuts down, once it announced it.
+#[derive(Resource)]
+pub struct ServerShutdown {
+    pub seconds_left: u32,
+}
 
-pub fn send_login(mut client: ResMut<RenetClient>, local_player: Res<LocalPlayer>) {
+pub fn send_login(mut client: NetClient) {
     info!("Sending login");
-    client.send_message(
-        DefaultChannel::ReliableOrdered,
-        ROMFromClient::PlayerLogin(PlayerLogin {
-            id: local_player.id,
-        }),
-    );
+    client.send(ROMFromClient::PlayerLogin(PlayerLogin {
+        protocol_version: PROTOCOL_VERSION,
+        resume: None,
+    }));
+}
+
+/// Reports why the connection to the server failed while logging in or in the lobby, returning
+/// to the main menu.
+pub fn check_connection(
+    transport: Res<NetcodeClientTransport>,
+    mut status: ResMut<MenuStatus>,
+    state: Res<State<ClientState>>,
+    mut next_state: ResMut<NextState<ClientState>>,
+) {
+    let Some(reason) = transport.disconnect_reason() else {
+        return;
+    };
+    if status.0.is_some() {
+        return;
+    }
+    if *state.get() == ClientState::Lobby {
+        next_state.set(ClientState::MainMenu);
+    }
+    let message = match reason {
+        // Netcode drops connection requests with a different protocol id without answering.
+        NetcodeDisconnectReason::ConnectionRequestTimedOut
+        | NetcodeDisconnectReason::ConnectionResponseTimedOut
+        | NetcodeDisconnectReason::ConnectionDenied => format!(
+            "Could not connect to the server ({}). It may be down, full or running a \
+            protocol version other than {:016x}.",
+            reason, PROTOCOL_VERSION
+        ),
+        reason => format!("Disconnected from the server: {}", reason),
+    };
+    warn!("{}", message);
+    status.0 = Some(message);
 }
 
+/// Handles the answer to a login, or to a reconnecting client resuming its session.
+#[allow(clippy::too_many_arguments)]
 pub fn handle_login(
     mut commands: Commands,
+    state: Res<State<ClientState>>,
     mut next_state: ResMut<NextState<ClientState>>,
-    server_messages: Res<ServerMessages>,
+    mut client: ResMut<RenetClient>,
+    mut status: ResMut<MenuStatus>,
+    mut local_player: ResMut<LocalPlayer>,
+    mut login_accepted: EventReader<FromServer<LoginAccepted>>,
+    mut login_rejected: EventReader<FromServer<LoginRejected>>,
 ) {
-    info!("Checking for login initial sync");
-    for message in server_messages.reliable_ordered.iter() {
-        match message {
-            ROMFromServer::GameSync(game_sync) => {
-                info!("Initial game sync {:?}", game_sync);
-                // Add one to initial frame to account for the frame we are currently on.
-                let init_frame =
-                    game_sync.frame + common::frames_since_unix_time(game_sync.unix_time) + 1;
-                info!("Starting game from frame: {}", init_frame);
-
-                commands.insert_resource(SyncFrameCount::new(init_frame));
-                commands.insert_resource(ComponentRollbacks::from_frame(init_frame - 1));
-                commands.insert_resource(GameSyncRequest::new(game_sync.clone()));
-                commands.insert_resource(RollbackRequest::default());
-                commands.insert_resource(InputRollback::from_frame(init_frame));
+    for FromServer {
+        message: LoginAccepted { player_id },
+    } in login_accepted.read()
+    {
+        info!("Logged in as player {}", player_id);
+        local_player.id = *player_id;
+        status.0 = None;
+        commands.remove_resource::<Reconnect>();
+        next_state.set(ClientState::Lobby);
+    }
 
-                commands.spawn(Camera2dBundle::default());
-                next_state.set(ClientState::InGame);
-            }
-            _ => {}
+    for FromServer {
+        message: LoginRejected { reason },
+    } in login_rejected.read()
+    {
+        warn!("Login rejected: {}", reason);
+        status.0 = Some(format!("Login rejected. {}", reason));
+        client.disconnect();
+        if *state.get() == ClientState::Reconnecting {
+            commands.remove_resource::<Reconnect>();
+            next_state.set(ClientState::MainMenu);
         }
     }
 }
 
-pub fn handle_game_events(
+/// A game joined in another instance while in game, started once the old world is torn down.
+#[derive(Resource)]
+pub struct Transfer(GameJoined);
+
+/// Starts the game once the room's match started or the session was resumed. In game, the
+/// player moved to another instance and the world is rebuilt from its game sync.
+pub fn join_game(
     mut commands: Commands,
-    server_messages: Res<ServerMessages>,
-    local_player: Res<LocalPlayer>,
-    mut server_entity_map: ResMut<ServerEntityMap>,
-    player_q: Query<(Entity, &Player, &ServerObject)>,
-    mut game_sync_req: ResMut<GameSyncRequest>,
+    state: Res<State<ClientState>>,
+    mut next_state: ResMut<NextState<ClientState>>,
+    mut local_player: ResMut<LocalPlayer>,
+    mut game_joined: EventReader<FromServer<GameJoined>>,
+    mut fixed_time: ResMut<Time<Fixed>>,
+    mut chat_box: ResMut<ChatBox>,
 ) {
-    for message in server_messages.reliable_ordered.iter() {
-        match message {
-            ROMFromServer::PlayerConnected {
-                player_data,
-                server_object,
-            } => {
-                if player_data.player.id != local_player.id {
-                    info!("Spawning remote player with id {}", player_data.player.id.0);
-                    let eid = commands
-                        .spawn(*server_object)
-                        .insert(player_data.clone())
-                        .insert(get_player_sprite(true))
-                        .id();
-                    server_entity_map.insert(*server_object, eid).unwrap();
-                }
-            }
-            ROMFromServer::PlayerDisconnected(player_id) => {
-                info!("Despawning remote player with id {}", player_id.0);
-                for (entity, player, server_object) in player_q.iter() {
-                    if &player.id == player_id {
-                        commands.entity(entity).despawn_recursive();
-                        server_entity_map.remove(server_object);
-                    }
-                }
-            }
-            ROMFromServer::GameSync(_) => {}
-        }
+    info!("Checking for initial game sync");
+    let Some(FromServer { message }) = game_joined.read().last() else {
+        return;
+    };
+    if *state.get() == ClientState::InGame {
+        info!("Moving to instance {}", message.instance);
+        commands.insert_resource(Transfer(message.clone()));
+        next_state.set(ClientState::Transferring);
+        return;
     }
+    start_game(
+        &mut commands,
+        &mut local_player,
+        &mut fixed_time,
+        &mut chat_box,
+        message,
+    );
+    next_state.set(ClientState::InGame);
+}
 
-    for message in server_messages.unreliable.iter() {
-        match message {
-            UMFromServer::GameSync(game_sync) => {
-                game_sync_req.request(game_sync.clone());
-                info!("Receving sync for frame {}", game_sync.frame);
-            }
-            _ => {}
-        }
+/// Starts the game in the instance the player moved to.
+pub fn finish_transfer(
+    mut commands: Commands,
+    transfer: Option<Res<Transfer>>,
+    mut next_state: ResMut<NextState<ClientState>>,
+    mut local_player: ResMut<LocalPlayer>,
+    mut fixed_time: ResMut<Time<Fixed>>,
+    mut chat_box: ResMut<ChatBox>,
+) {
+    let Some(transfer) = transfer else {
+        return;
+    };
+    start_game(
+        &mut commands,
+        &mut local_player,
+        &mut fixed_time,
+        &mut chat_box,
+        &transfer.0,
+    );
+    commands.remove_resource::<Transfer>();
+    next_state.set(ClientState::InGame);
+}
+
+fn start_game(
+    commands: &mut Commands,
+    local_player: &mut LocalPlayer,
+    fixed_time: &mut Time<Fixed>,
+    chat_box: &mut ChatBox,
+    GameJoined {
+        player_id,
+        instance,
+        frame_duration,
+        session,
+        game_sync,
+        ..
+    }: &GameJoined,
+) {
+    info!("Joined instance {} as player {}", instance, player_id);
+    local_player.id = *player_id;
+    local_player.session = Some(*session);
+    commands.remove_resource::<Reconnect>();
+    chat_box.push(format!("* Entered instance {}.", instance));
+
+    info!("Initial game sync {:?}", game_sync);
+    fixed_time.set_timestep(*frame_duration);
+    // Add one to initial frame to account for the frame we are currently on.
+    let init_frame =
+        game_sync.frame + common::frames_since_unix_time(game_sync.unix_time, *frame_duration) + 1;
+    info!("Starting game from frame: {}", init_frame);
+
+    commands.insert_resource(SyncFrameCount::new(init_frame));
+    commands.insert_resource(ComponentRollbacks::from_frame(init_frame - 1));
+    commands.insert_resource(GameSyncRequest::new(game_sync.clone()));
+    commands.insert_resource(RollbackRequest::default());
+    commands.insert_resource(InputRollback::from_frame(init_frame));
+}
+
+pub fn handle_shutdown_notice(
+    mut commands: Commands,
+    mut shutdown_notices: EventReader<FromServer<ShutdownNotice>>,
+) {
+    if let Some(FromServer {
+        message: ShutdownNotice { seconds_left },
+    }) = shutdown_notices.read().last()
+    {
+        warn!("Server shutting down in {} seconds", seconds_left);
+        commands.insert_resource(ServerShutdown {
+            seconds_left: *seconds_left,
+        });
+    }
+}
+
+/// Tears down the world and the rollback state when leaving the game. Logging in again,
+/// resuming the session or moving to another instance starts over from the server's game sync.
+pub fn leave_game(
+    mut commands: Commands,
+    mut server_messages: ResMut<ServerMessages>,
+    replicated_q: Query<Entity, With<Replicated>>,
+) {
+    for entity in replicated_q.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+    server_messages.remove_sender(&());
+    commands.insert_resource(ServerEntityMap::default());
+    commands.insert_resource(SyncHistory::default());
+    commands.insert_resource(InputAccumulator::default());
+    commands.insert_resource(UnackedInputs::default());
+    commands.remove_resource::<SyncFrameCount>();
+    commands.remove_resource::<ComponentRollbacks>();
+    commands.remove_resource::<GameSyncRequest>();
+    commands.remove_resource::<RollbackRequest>();
+    commands.remove_resource::<InputRollback>();
+}
+
+pub fn handle_game_events(
+    mut game_syncs: EventReader<FromServer<GameSync>>,
+    mut game_sync_deltas: EventReader<FromServer<GameSyncDelta>>,
+    mut game_sync_req: ResMut<GameSyncRequest>,
+    mut sync_history: ResMut<SyncHistory>,
+    mut client: NetClient,
+) {
+    // Each sync is kept as the client's view of the world, only the objects it updated are
+    // applied.
+    let mut received_syncs = game_syncs
+        .read()
+        .map(|FromServer { message }| (message.clone(), message.clone()))
+        .collect::<Vec<_>>();
+    for FromServer { message: delta } in game_sync_deltas.read() {
+        let Some(baseline) = sync_history.get(delta.baseline_frame) else {
+            warn!(
+                "Missing baseline {} for sync delta on frame {}",
+                delta.baseline_frame, delta.frame
+            );
+            continue;
+        };
+        let view = delta.apply(baseline);
+        let game_sync = view.filtered(|server_obj| delta.contains(server_obj));
+        received_syncs.push((view, game_sync));
+    }
+    for (view, game_sync) in received_syncs {
+        info!("Receving sync for frame {}", game_sync.frame);
+        client.send(UMFromClient::GameSyncAck(GameSyncAck(game_sync.frame)));
+        game_sync_req.request(game_sync);
+        sync_history.push(view);
     }
 }
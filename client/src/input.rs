@@ -1,74 +1,205 @@
-use bevy::prelude::*;
-use bevy_renet::renet::{DefaultChannel, RenetClient};
+use std::collections::VecDeque;
+
+use bevy::{prelude::*, window::PrimaryWindow};
 use common::{
-    rollback::{InputRollback, RollbackRequest, SyncFrameCount},
-    IdPlayerInput, RawPlayerInput, UMFromClient, UMFromServer,
+    channel::NetClient,
+    message::FromServer,
+    rollback::{InputRollback, RollbackRequest, SyncFrameCount, DEFAULT_ROLLBACK_WINDOW},
+    FramedPlayerInput, IdPlayerInput, IdPlayerInputs, InputAck, InputsReceived, Player,
+    PlayerInputs, RawPlayerInput, UMFromClient, INPUT_REDUNDANCY,
 };
 
-use crate::{messages::ServerMessages, LocalPlayer};
+use crate::{ui::chat::ChatBox, LocalPlayer};
 
-pub fn read_inputs(
-    mut input_rollback: ResMut<InputRollback>,
-    local_player: Res<LocalPlayer>,
+/// Local input sampled at render rate since the last fixed tick.
+#[derive(Resource, Default)]
+pub struct InputAccumulator(Option<RawPlayerInput>);
+
+/// Local inputs the server has not acknowledged yet, oldest first. These are resent with
+/// every input packet.
+#[derive(Resource, Default)]
+pub struct UnackedInputs(VecDeque<FramedPlayerInput>);
+
+impl UnackedInputs {
+    /// Returns the number of inputs dropped without being acknowledged.
+    fn push(&mut self, input: FramedPlayerInput) -> u64 {
+        self.0.push_back(input);
+        let mut dropped = 0;
+        while self.0.len() > INPUT_REDUNDANCY {
+            self.0.pop_front();
+            dropped += 1;
+        }
+        dropped
+    }
+
+    /// Trims every input up to and including `frame`.
+    fn acknowledge_up_to(&mut self, frame: u64) {
+        self.0.retain(|input| input.frame > frame);
+    }
+
+    /// Drops inputs too old to be applied on their own frame by the server anymore, returning
+    /// how many were dropped.
+    fn expire(&mut self, current_frame: u64) -> u64 {
+        let oldest_frame = current_frame.saturating_sub(DEFAULT_ROLLBACK_WINDOW as u64);
+        let len = self.0.len();
+        self.0.retain(|input| input.frame >= oldest_frame);
+        (len - self.0.len()) as u64
+    }
+}
+
+/// Counts of local inputs sent to the server and those never acknowledged by it.
+#[derive(Resource, Default)]
+pub struct InputStats {
+    pub sent: u64,
+    pub lost: u64,
+    pub acked_frame: Option<u64>,
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn sample_inputs(
     keyboard_input: Res<Input<KeyCode>>,
-    server_messages: ResMut<ServerMessages>,
-    mut rollback_request: ResMut<RollbackRequest>,
-    frame: Res<SyncFrameCount>,
-    mut client: ResMut<RenetClient>,
+    mouse_input: Res<Input<MouseButton>>,
+    window_q: Query<&Window, With<PrimaryWindow>>,
+    camera_q: Query<(&Camera, &GlobalTransform)>,
+    player_q: Query<(&Player, &Transform)>,
+    local_player: Res<LocalPlayer>,
+    mut accumulator: ResMut<InputAccumulator>,
+    chat_box: Res<ChatBox>,
 ) {
-    let mut had_input = false;
+    // Keys typed into the chat box do not move the player.
+    let pressed = |key| !chat_box.is_typing() && keyboard_input.pressed(key);
 
-    // Collect local player input.
     let mut input = RawPlayerInput::default();
-    if keyboard_input.pressed(KeyCode::W) {
+    if pressed(KeyCode::W) {
         input.y_move += 1;
-        had_input = true;
     }
-    if keyboard_input.pressed(KeyCode::S) {
+    if pressed(KeyCode::S) {
         input.y_move -= 1;
-        had_input = true;
     }
-    if keyboard_input.pressed(KeyCode::A) {
+    if pressed(KeyCode::A) {
         input.x_move -= 1;
-        had_input = true;
     }
-    if keyboard_input.pressed(KeyCode::D) {
+    if pressed(KeyCode::D) {
         input.x_move += 1;
-        had_input = true;
     }
-    if keyboard_input.pressed(KeyCode::Space) {
+    if pressed(KeyCode::Space) || mouse_input.pressed(MouseButton::Left) {
         input.shoot = true;
-        had_input = true;
     }
 
-    if had_input {
-        input_rollback.accept_input(IdPlayerInput {
-            player_id: local_player.id,
-            input: input.at_frame(frame.count()),
+    // Aim from the local player towards the cursor.
+    let cursor = window_q
+        .get_single()
+        .ok()
+        .and_then(|window| window.cursor_position())
+        .zip(camera_q.get_single().ok())
+        .and_then(|(cursor, (camera, camera_transform))| {
+            camera.viewport_to_world_2d(camera_transform, cursor)
         });
-        // @TODO - apply mock input latency.
-        client.send_message(
-            DefaultChannel::Unreliable,
-            UMFromClient::PlayerInput(input),
-        );
+    let local_translation = player_q
+        .iter()
+        .find_map(|(player, transform)| (player.id == local_player.id).then_some(transform))
+        .map(|transform| transform.translation.truncate());
+    if let Some((cursor, local_translation)) = cursor.zip(local_translation) {
+        let aim = cursor - local_translation;
+        if aim != Vec2::ZERO {
+            input.x_aim = aim.y.atan2(aim.x);
+        }
     }
 
-    for message in server_messages.unreliable.iter() {
-        match message {
-            UMFromServer::IdPlayerInput(id_player_input) => {
-                info!(
-                    "Accepting input on frame {} from {}, current frame is {}",
-                    id_player_input.input.frame,
-                    id_player_input.player_id,
-                    frame.count()
-                );
-
-                input_rollback.accept_input(*id_player_input);
-                if id_player_input.input.frame < frame.count() {
-                    rollback_request.request(id_player_input.input.frame);
+    // Folded in even when idle, so a released key clears the axis it held.
+    match accumulator.0.as_mut() {
+        Some(accumulated) => accumulated.aggregate(&input),
+        None => accumulator.0 = Some(input),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn read_inputs(
+    mut input_rollback: ResMut<InputRollback>,
+    local_player: Res<LocalPlayer>,
+    mut accumulator: ResMut<InputAccumulator>,
+    mut unacked_inputs: ResMut<UnackedInputs>,
+    mut input_stats: ResMut<InputStats>,
+    mut id_player_inputs: EventReader<FromServer<IdPlayerInputs>>,
+    mut inputs_received: EventReader<FromServer<InputsReceived>>,
+    mut input_acks: EventReader<FromServer<InputAck>>,
+    mut rollback_request: ResMut<RollbackRequest>,
+    frame: Res<SyncFrameCount>,
+    mut client: NetClient,
+) {
+    // Fold local input sampled since the last tick into this frame. Input is sent every
+    // frame, even when idle, so the server can acknowledge a contiguous range of frames.
+    let mut input = accumulator.0.take().unwrap_or_default();
+    // Remote players are seen roughly a round trip behind, which is also how long local
+    // inputs take to be acknowledged.
+    input.view_delay = input_stats.acked_frame.map_or(0, |acked_frame| {
+        frame
+            .count()
+            .saturating_sub(acked_frame)
+            .min(u8::MAX as u64) as u8
+    });
+    let framed_input = input.at_frame(frame.count());
+    input_rollback.accept_input(IdPlayerInput {
+        player_id: local_player.id,
+        input: framed_input,
+    });
+    input_stats.sent += 1;
+    input_stats.lost += unacked_inputs.push(framed_input);
+    input_stats.lost += unacked_inputs.expire(frame.count());
+
+    client.send(UMFromClient::PlayerInput(PlayerInputs(
+        unacked_inputs.0.iter().copied().collect(),
+    )));
+
+    for FromServer {
+        message: IdPlayerInputs(id_player_inputs),
+    } in id_player_inputs.read()
+    {
+        for id_player_input in id_player_inputs.iter() {
+            // Inputs are relayed several times to survive packet loss.
+            if !input_rollback.mark_received(id_player_input.player_id, id_player_input.input.frame)
+            {
+                continue;
+            }
+            info!(
+                "Accepting input on frame {} from {}, current frame is {}",
+                id_player_input.input.frame,
+                id_player_input.player_id,
+                frame.count()
+            );
+
+            input_rollback.accept_input(*id_player_input);
+            if id_player_input.input.frame < frame.count() {
+                rollback_request.request(id_player_input.input.frame);
+            }
+        }
+    }
+
+    for FromServer {
+        message: InputsReceived(received_frame),
+    } in inputs_received.read()
+    {
+        unacked_inputs.acknowledge_up_to(*received_frame);
+        input_stats.acked_frame = Some(*received_frame);
+    }
+
+    for FromServer { message: ack } in input_acks.read() {
+        match ack {
+            InputAck::Shifted { from, to } => {
+                info!("Server shifted local input from frame {} to {}", from, to);
+                input_rollback.shift_input(local_player.id, *from, *to);
+                if *from < frame.count() {
+                    rollback_request.request(*from.min(to));
+                }
+            }
+            InputAck::Rejected { frame: rejected } => {
+                warn!("Server rejected local input for frame {}", rejected);
+                input_rollback.remove_input(local_player.id, *rejected);
+                if *rejected < frame.count() {
+                    rollback_request.request(*rejected);
                 }
             }
-            _ => {}
+            InputAck::Accepted { .. } => {}
         }
     }
 }
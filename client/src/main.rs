@@ -1,39 +1,103 @@
+use auth::IssuedToken;
 use bevy::prelude::*;
 use bevy_renet::{
     renet::{
         transport::{ClientAuthentication, NetcodeClientTransport},
-        ConnectionConfig, RenetClient,
+        RenetClient,
     },
     transport::NetcodeClientPlugin,
     RenetClientPlugin,
 };
 use clap::Parser;
 use common::{
+    channel::connection_config,
+    conditioner::{DirectionConditions, LinkConditioner, LinkConditions},
+    delta::SyncHistory,
     game::GameLogicPlugin,
+    message::ClientMessagesPlugin,
+    replication::{AppReplicationExt, ReplicationPlugin, ReplicationPluginClient},
     rollback::RollbackPluginClient,
     schedule::{ClientSchedule, ClientSchedulePlugin, ClientState},
-    PlayerId, ServerEntityMap,
+    stats::NetworkStatsPluginClient,
+    PlayerId, ServerEntityMap, SessionToken,
 };
-use events::{handle_login, send_login};
-use messages::{ServerMessages, ServerMessageBuffer};
+use events::{check_connection, handle_login, join_game, send_login};
+use messages::ServerMessages;
+use reconnect::ConnectionAddresses;
 use spawn::attach_player_sprite;
-use std::{net::UdpSocket, sync::OnceLock, time::SystemTime};
+use std::{
+    fs::File,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    path::PathBuf,
+    sync::OnceLock,
+    time::{Duration, SystemTime},
+};
 use ui::UIPlugin;
 
 mod events;
 mod input;
 mod messages;
+mod reconnect;
 mod spawn;
 mod ui;
 
 #[derive(Parser, Debug)]
 struct Args {
+    /// Address of the server, the token must be issued for it.
+    #[arg(long, default_value = "127.0.0.1:5000")]
+    server: SocketAddr,
+
+    /// Connect token issued by `auth token`.
+    #[arg(long)]
+    token: Option<PathBuf>,
+
+    /// Player id to issue a token for in process when no `--token` is given.
     #[arg(long, default_value_t = 0)]
     id: u64,
 
-    /// Mocked extra latency in milliseconds.
+    /// Private key used to issue a token in process when no `--token` is given.
+    #[arg(long, default_value = "private.key")]
+    private_key: PathBuf,
+
+    /// Simulated latency of messages from the server in milliseconds.
     #[arg(short, long, default_value_t = 0.0)]
     network_latency: f32,
+
+    /// Largest random delay added to the simulated latency in milliseconds.
+    #[arg(long, default_value_t = 0.0)]
+    jitter: f32,
+
+    /// Chance from 0 to 1 that an unreliable message from the server is dropped.
+    #[arg(long, default_value_t = 0.0)]
+    packet_loss: f32,
+
+    /// Chance from 0 to 1 that an unreliable message from the server is delivered twice.
+    #[arg(long, default_value_t = 0.0)]
+    duplication: f32,
+
+    /// Chance from 0 to 1 that an unreliable message from the server is delivered late.
+    #[arg(long, default_value_t = 0.0)]
+    reordering: f32,
+}
+
+impl Args {
+    /// Conditions of messages from the server. Messages to the server are conditioned by the
+    /// server.
+    fn link_conditioner(&self) -> LinkConditioner {
+        LinkConditioner {
+            server_to_client: DirectionConditions {
+                default: LinkConditions {
+                    latency: Duration::from_secs_f32(self.network_latency / 1000.0),
+                    jitter: Duration::from_secs_f32(self.jitter / 1000.0),
+                    loss: self.packet_loss,
+                    duplication: self.duplication,
+                    reordering: self.reordering,
+                },
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
 }
 
 static ARGS: OnceLock<Args> = OnceLock::new();
@@ -45,14 +109,37 @@ fn main() {
     app.add_plugins(DefaultPlugins)
         .add_state::<ClientState>()
         .add_plugins(ClientSchedulePlugin)
+        .add_plugins(ClientMessagesPlugin)
+        .add_plugins((ReplicationPlugin, ReplicationPluginClient))
+        .add_plugins(NetworkStatsPluginClient)
         .add_plugins(RollbackPluginClient)
         .add_plugins(GameLogicPlugin)
         .add_plugins(UIPlugin)
         .add_systems(Startup, send_login)
         .add_systems(
             FixedUpdate,
-            handle_login.run_if(in_state(ClientState::MainMenu)),
+            (
+                handle_login.run_if(
+                    in_state(ClientState::MainMenu).or_else(in_state(ClientState::Reconnecting)),
+                ),
+                join_game.run_if(
+                    in_state(ClientState::Lobby)
+                        .or_else(in_state(ClientState::Reconnecting))
+                        .or_else(in_state(ClientState::InGame)),
+                ),
+            ),
+        )
+        .add_systems(
+            Update,
+            (
+                check_connection
+                    .run_if(in_state(ClientState::MainMenu).or_else(in_state(ClientState::Lobby))),
+                reconnect::detect_disconnect.run_if(in_state(ClientState::InGame)),
+                reconnect::reconnect.run_if(in_state(ClientState::Reconnecting)),
+            ),
         )
+        .add_systems(OnExit(ClientState::InGame), events::leave_game)
+        .add_systems(OnEnter(ClientState::Transferring), events::finish_transfer)
         .add_systems(
             FixedUpdate,
             messages::receive_messages.in_set(ClientSchedule::ServerMessageCollection),
@@ -61,51 +148,94 @@ fn main() {
             FixedUpdate,
             (
                 input::read_inputs.in_set(ClientSchedule::InputCollection),
-                events::handle_game_events.in_set(ClientSchedule::ServerEventHandling),
+                (events::handle_game_events, events::handle_shutdown_notice)
+                    .in_set(ClientSchedule::ServerEventHandling),
             )
                 .run_if(in_state(ClientState::InGame)),
         )
         .add_systems(
-            FixedUpdate,
-            attach_player_sprite
-                .in_set(ClientSchedule::ServerReactive)
-                .run_if(in_state(ClientState::InGame)),
+            Update,
+            input::sample_inputs.run_if(in_state(ClientState::InGame)),
         )
+        .add_spawn_hook(attach_player_sprite)
+        .init_resource::<input::InputAccumulator>()
+        .init_resource::<input::UnackedInputs>()
+        .init_resource::<input::InputStats>()
         .init_resource::<ServerMessages>()
-        // @TODO fix this
-        .init_resource::<ServerMessageBuffer>()
+        .insert_resource(ARGS.get().unwrap().link_conditioner())
         .init_resource::<ServerEntityMap>()
-        .insert_resource(common::fixed_timestep_rate())
-        .insert_resource(LocalPlayer {
-            id: PlayerId(ARGS.get().unwrap().id),
-        });
+        .init_resource::<SyncHistory>()
+        .insert_resource(common::fixed_timestep_rate());
 
     app.add_plugins(RenetClientPlugin);
 
-    let client = RenetClient::new(ConnectionConfig::default());
+    let client = RenetClient::new(connection_config());
     app.insert_resource(client);
 
     // Setup the transport layer
     app.add_plugins(NetcodeClientPlugin);
 
-    let server_addr = "127.0.0.1:5000".parse().unwrap();
-    let authentication = ClientAuthentication::Unsecure {
-        server_addr,
-        client_id: ARGS.get().unwrap().id,
-        user_data: None,
-        protocol_id: 0,
+    let server_addr = ARGS.get().unwrap().server;
+    // Any interface, so that servers on other hosts are reachable.
+    let local_ip: IpAddr = if server_addr.is_ipv4() {
+        Ipv4Addr::UNSPECIFIED.into()
+    } else {
+        Ipv6Addr::UNSPECIFIED.into()
+    };
+    let socket = UdpSocket::bind(SocketAddr::new(local_ip, 0)).unwrap();
+    app.insert_resource(ConnectionAddresses {
+        server: server_addr,
+        local: socket.local_addr().unwrap(),
+    });
+    let (transport, player_id) = connect(server_addr, socket);
+    app.insert_resource(LocalPlayer {
+        id: player_id,
+        session: None,
+    });
+
+    app.insert_resource(transport);
+    app.run();
+}
+
+/// Connects to the server from `socket`, returning the transport and the player its token was
+/// issued for.
+fn connect(server_addr: SocketAddr, socket: UdpSocket) -> (NetcodeClientTransport, PlayerId) {
+    let issued_token = issue_token(server_addr);
+    let authentication = ClientAuthentication::Secure {
+        connect_token: issued_token.connect_token,
     };
-    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
     let current_time = SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)
         .unwrap();
     let transport = NetcodeClientTransport::new(current_time, authentication, socket).unwrap();
+    (transport, issued_token.player_id)
+}
 
-    app.insert_resource(transport);
-    app.run();
+/// Reads the connect token given with `--token`, or issues one in process for `--id` when
+/// running locally with access to the private key.
+fn issue_token(server_addr: SocketAddr) -> IssuedToken {
+    let args = ARGS.get().unwrap();
+    if let Some(path) = &args.token {
+        let mut file = File::open(path)
+            .unwrap_or_else(|err| panic!("Failed to open {}: {}", path.display(), err));
+        return IssuedToken::read(&mut file)
+            .unwrap_or_else(|err| panic!("Failed to read token {}: {}", path.display(), err));
+    }
+
+    let private_key = auth::read_private_key(&args.private_key).unwrap_or_else(|err| {
+        panic!(
+            "Failed to read private key {}: {}. Pass a token with --token instead",
+            args.private_key.display(),
+            err
+        )
+    });
+    IssuedToken::issue(&private_key, PlayerId(args.id), vec![server_addr])
+        .unwrap_or_else(|err| panic!("Failed to issue token: {}", err))
 }
 
 #[derive(Resource)]
 struct LocalPlayer {
     id: PlayerId,
+    /// Token to resume the session with after the connection drops, once logged in.
+    session: Option<SessionToken>,
 }
@@ -1,65 +1,56 @@
-use std::collections::VecDeque;
+use std::time::Instant;
 
 use bevy::prelude::*;
-use bevy_renet::renet::{DefaultChannel, RenetClient};
-use common::{ROMFromServer, UMFromServer, FRAME_DURATION_SECONDS};
-
-use crate::ARGS;
-
-#[derive(Default, Resource, Clone)]
-pub struct ServerMessages {
-    pub unreliable: Vec<UMFromServer>,
-    pub reliable_ordered: Vec<ROMFromServer>,
+use bevy_renet::renet::RenetClient;
+use common::{
+    conditioner::{ConditionedMessages, LinkConditioner},
+    message::ServerMessageEvents,
+    stats::NetworkStats,
+    ROMFromServer, SnapshotFromServer, UMFromServer,
+};
+
+/// Messages from the server delayed by the `LinkConditioner`.
+pub type ServerMessages = ConditionedMessages<()>;
+
+/// Decodes messages from the server, once the `LinkConditioner` delivers them, into
+/// `FromServer` events.
+pub fn receive_messages(world: &mut World) {
+    let now = Instant::now();
+    receive::<UMFromServer>(world, now);
+    receive::<SnapshotFromServer>(world, now);
+    receive::<ROMFromServer>(world, now);
 }
 
-/// Keeps a history of server messages.
-/// [most recent, ..., oldest]
-/// Length of buffer will be determined by the read latency.
-#[derive(Default, Resource)]
-pub struct ServerMessageBuffer(VecDeque<ServerMessages>);
-
-impl ServerMessageBuffer {
-    fn has_buffer(&self) -> bool {
-        self.0.len() as f32 * FRAME_DURATION_SECONDS as f32
-            > ARGS.get().unwrap().network_latency / 1000.0
-    }
-
-    fn read(&mut self) -> ServerMessages {
-        if self.has_buffer() {
-            self.0.pop_back().unwrap()
-        } else {
-            ServerMessages::default()
+fn receive<M: ServerMessageEvents>(world: &mut World, now: Instant) {
+    let channel = M::CHANNEL.into();
+    let conditions = *world
+        .resource::<LinkConditioner>()
+        .direction(M::DIRECTION)
+        .get(channel);
+    world.resource_scope(|world, mut client: Mut<RenetClient>| {
+        let mut received = 0;
+        let mut server_messages = world.resource_mut::<ServerMessages>();
+        while let Some(bytes) = client.receive_message(channel) {
+            received += bytes.len();
+            server_messages.push(
+                &conditions,
+                M::CHANNEL.is_reliable(),
+                (),
+                channel,
+                bytes,
+                now,
+            );
         }
-    }
-
-    fn write(&mut self, buffer: ServerMessages) {
-        self.0.push_front(buffer);
-    }
-}
-
-pub fn receive_messages(
-    mut client: ResMut<RenetClient>,
-    mut buffer: ResMut<ServerMessageBuffer>,
-    mut messages: ResMut<ServerMessages>,
-) {
-    let mut next_buffer = ServerMessages::default();
-
-    while let Some(message) = client.receive_message(DefaultChannel::Unreliable) {
-        if let Ok(um) = UMFromServer::try_from(message) {
-            next_buffer.unreliable.push(um);
-        } else {
-            warn!("Received unparsable unreliable message from server");
-        };
-    }
-
-    while let Some(message) = client.receive_message(DefaultChannel::ReliableOrdered) {
-        if let Ok(rom) = ROMFromServer::try_from(message) {
-            next_buffer.reliable_ordered.push(rom);
-        } else {
-            warn!("Received unparsable reliable ordered message from server");
+        world
+            .resource_mut::<NetworkStats>()
+            .count_received(channel, received);
+    });
+
+    let messages = world.resource_mut::<ServerMessages>().deliver(channel, now);
+    for (_, bytes) in messages {
+        match M::decode(&bytes) {
+            Ok(message) => message.send_events(world),
+            Err(err) => warn!("Failed to decode message from server: {}", err),
         }
     }
-
-    buffer.write(next_buffer);
-    *messages = buffer.read();
 }
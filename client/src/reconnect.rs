@@ -0,0 +1,125 @@
+use std::{
+    net::{SocketAddr, UdpSocket},
+    time::Duration,
+};
+
+use bevy::prelude::*;
+use bevy_renet::renet::{transport::NetcodeClientTransport, RenetClient};
+use common::{
+    channel::{connection_config, send_to_server},
+    protocol::PROTOCOL_VERSION,
+    schedule::ClientState,
+    stats::NetworkStats,
+    PlayerLogin, ROMFromClient,
+};
+
+use crate::{connect, events::ServerShutdown, ui::menu::MenuStatus, LocalPlayer};
+
+/// Attempts at reconnecting before giving up. Each attempt can last until netcode times out.
+const MAX_RECONNECT_ATTEMPTS: u32 = 5;
+/// Delay before each attempt at reconnecting.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Addresses of the connection to the server. Reconnecting binds the same local address, so
+/// that a connect token read from a file can be used again.
+#[derive(Resource)]
+pub struct ConnectionAddresses {
+    pub server: SocketAddr,
+    pub local: SocketAddr,
+}
+
+#[derive(Resource)]
+pub struct Reconnect {
+    attempts: u32,
+    delay: Timer,
+}
+
+impl Default for Reconnect {
+    fn default() -> Self {
+        Self {
+            attempts: 0,
+            delay: Timer::new(RECONNECT_DELAY, TimerMode::Once),
+        }
+    }
+}
+
+/// Starts reconnecting when the connection drops in game, or returns to the menu if the
+/// server announced it was shutting down.
+pub fn detect_disconnect(
+    mut commands: Commands,
+    client: Res<RenetClient>,
+    shutdown: Option<Res<ServerShutdown>>,
+    mut next_state: ResMut<NextState<ClientState>>,
+    mut status: ResMut<MenuStatus>,
+) {
+    let Some(reason) = client.disconnect_reason() else {
+        return;
+    };
+    if shutdown.is_some() {
+        info!("Server shut down");
+        status.0 = Some("The server shut down.".to_string());
+        commands.remove_resource::<ServerShutdown>();
+        next_state.set(ClientState::MainMenu);
+        return;
+    }
+    warn!("Connection lost: {}", reason);
+    status.0 = Some(format!("Connection lost: {}. Reconnecting...", reason));
+    commands.init_resource::<Reconnect>();
+    next_state.set(ClientState::Reconnecting);
+}
+
+/// Connects again after a delay whenever the last attempt failed, logging in with the session
+/// token of the dropped connection. Gives up after `MAX_RECONNECT_ATTEMPTS`.
+pub fn reconnect(world: &mut World) {
+    if !world.resource::<RenetClient>().is_disconnected() {
+        return;
+    }
+    let delta = world.resource::<Time>().delta();
+    let mut reconnect = world.resource_mut::<Reconnect>();
+    if !reconnect.delay.tick(delta).finished() {
+        return;
+    }
+    if reconnect.attempts >= MAX_RECONNECT_ATTEMPTS {
+        let message = format!(
+            "Could not reconnect to the server after {} attempts.",
+            MAX_RECONNECT_ATTEMPTS
+        );
+        warn!("{}", message);
+        world.resource_mut::<MenuStatus>().0 = Some(message);
+        world.remove_resource::<Reconnect>();
+        world
+            .resource_mut::<NextState<ClientState>>()
+            .set(ClientState::MainMenu);
+        return;
+    }
+    reconnect.attempts += 1;
+    reconnect.delay.reset();
+    let attempts = reconnect.attempts;
+    info!("Reconnecting, attempt {}", attempts);
+    world.resource_mut::<MenuStatus>().0 = Some(format!(
+        "Connection lost. Reconnecting (attempt {} of {})...",
+        attempts, MAX_RECONNECT_ATTEMPTS
+    ));
+
+    // The old socket must be closed before its address can be bound again.
+    world.remove_resource::<NetcodeClientTransport>();
+    let addresses = world.resource::<ConnectionAddresses>();
+    let server = addresses.server;
+    let socket = UdpSocket::bind(addresses.local)
+        .or_else(|_| UdpSocket::bind(SocketAddr::new(addresses.local.ip(), 0)))
+        .unwrap();
+    let (transport, _) = connect(server, socket);
+    world.insert_resource(transport);
+
+    let mut client = RenetClient::new(connection_config());
+    let resume = world.resource::<LocalPlayer>().session;
+    send_to_server(
+        &mut client,
+        &mut world.resource_mut::<NetworkStats>(),
+        ROMFromClient::PlayerLogin(PlayerLogin {
+            protocol_version: PROTOCOL_VERSION,
+            resume,
+        }),
+    );
+    world.insert_resource(client);
+}
@@ -0,0 +1,185 @@
+use std::collections::VecDeque;
+
+use bevy::prelude::*;
+use common::{
+    channel::NetClient, message::FromServer, ChatMessage, ChatRequest, ChatScope, InstanceId,
+    PlayerId, ROMFromClient, TransferRequest, MAX_CHAT_LENGTH,
+};
+
+/// Chat messages shown at once, older ones are dropped.
+const CHAT_LOG_LENGTH: usize = 8;
+
+/// Chat messages received and the message being typed. Enter opens the chat box and sends,
+/// escape closes it. Messages starting with `/l` go to nearby players and `/w <id>` whispers
+/// to a player, others go to everyone. `/join <id>` moves to another instance.
+#[derive(Resource, Default)]
+pub struct ChatBox {
+    typing: bool,
+    input: String,
+    log: VecDeque<String>,
+}
+
+impl ChatBox {
+    /// Keys are typed into the chat box rather than moving the player.
+    pub fn is_typing(&self) -> bool {
+        self.typing
+    }
+
+    pub fn push(&mut self, line: String) {
+        self.log.push_back(line);
+        if self.log.len() > CHAT_LOG_LENGTH {
+            self.log.pop_front();
+        }
+    }
+}
+
+#[derive(Component)]
+pub struct ChatLogText;
+
+#[derive(Component)]
+pub struct ChatInputText;
+
+pub fn setup_chat(mut commands: Commands) {
+    let style = TextStyle {
+        font_size: 20.0,
+        ..Default::default()
+    };
+    commands
+        .spawn(NodeBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                left: Val::Px(10.0),
+                bottom: Val::Px(10.0),
+                flex_direction: FlexDirection::Column,
+                ..default()
+            },
+            ..default()
+        })
+        .with_children(|parent| {
+            parent
+                .spawn(ChatLogText)
+                .insert(TextBundle::from_section("", style.clone()));
+            parent
+                .spawn(ChatInputText)
+                .insert(TextBundle::from_section("", style));
+        });
+}
+
+/// Reads `/l` and `/w <id>` prefixes, and `/join <instance>` which moves to another instance.
+/// Returns `None` for a whisper without a player id or a join without an instance id.
+fn parse_chat(input: &str) -> Option<ROMFromClient> {
+    if let Some(instance) = input.strip_prefix("/join ") {
+        return Some(ROMFromClient::Transfer(TransferRequest {
+            instance: InstanceId(instance.trim().parse().ok()?),
+        }));
+    }
+    let (scope, text) = if let Some(text) = input.strip_prefix("/l ") {
+        (ChatScope::Proximity, text)
+    } else if let Some(rest) = input.strip_prefix("/w ") {
+        let (player_id, text) = rest.split_once(' ')?;
+        (ChatScope::Whisper(PlayerId(player_id.parse().ok()?)), text)
+    } else {
+        (ChatScope::Global, input)
+    };
+    Some(ROMFromClient::Chat(ChatRequest {
+        scope,
+        text: text.to_string(),
+    }))
+}
+
+pub fn type_chat(
+    keyboard_input: Res<Input<KeyCode>>,
+    mut characters: EventReader<ReceivedCharacter>,
+    mut chat_box: ResMut<ChatBox>,
+    mut client: NetClient,
+) {
+    if !chat_box.typing {
+        characters.clear();
+        if keyboard_input.just_pressed(KeyCode::Return) {
+            chat_box.typing = true;
+        }
+        return;
+    }
+
+    if keyboard_input.just_pressed(KeyCode::Escape) {
+        chat_box.typing = false;
+        chat_box.input.clear();
+        return;
+    }
+    if keyboard_input.just_pressed(KeyCode::Return) {
+        chat_box.typing = false;
+        let input = std::mem::take(&mut chat_box.input);
+        if input.trim().is_empty() {
+            return;
+        }
+        match parse_chat(&input) {
+            Some(message) => client.send(message),
+            None => chat_box.push(
+                "* Whisper with /w <player id> <message>, move with /join <instance id>"
+                    .to_string(),
+            ),
+        }
+        return;
+    }
+    if keyboard_input.just_pressed(KeyCode::Back) {
+        chat_box.input.pop();
+    }
+    for ReceivedCharacter { char, .. } in characters.read() {
+        // Leaves room for a `/w <id>` prefix, the server checks the length of the message itself.
+        if !char.is_control() && chat_box.input.chars().count() < MAX_CHAT_LENGTH + 16 {
+            chat_box.input.push(*char);
+        }
+    }
+}
+
+pub fn receive_chat(
+    mut chat_messages: EventReader<FromServer<ChatMessage>>,
+    mut chat_box: ResMut<ChatBox>,
+) {
+    for FromServer {
+        message: ChatMessage {
+            sender,
+            scope,
+            text,
+        },
+    } in chat_messages.read()
+    {
+        let Some(sender) = sender else {
+            chat_box.push(format!("* {}", text));
+            continue;
+        };
+        chat_box.push(match scope {
+            ChatScope::Global => format!("{}: {}", sender, text),
+            ChatScope::Proximity => format!("[nearby] {}: {}", sender, text),
+            ChatScope::Whisper(recipient) => format!("[{} to {}] {}", sender, recipient, text),
+        });
+    }
+}
+
+/// Stops typing when leaving the game, so that movement works on the next login.
+pub fn close_chat_box(mut chat_box: ResMut<ChatBox>) {
+    chat_box.typing = false;
+    chat_box.input.clear();
+}
+
+pub fn update_chat_text(
+    chat_box: Res<ChatBox>,
+    mut log_q: Query<&mut Text, (With<ChatLogText>, Without<ChatInputText>)>,
+    mut input_q: Query<&mut Text, (With<ChatInputText>, Without<ChatLogText>)>,
+) {
+    if !chat_box.is_changed() {
+        return;
+    }
+    let log = chat_box.log.iter().cloned().collect::<Vec<_>>().join("\n");
+    for mut text in log_q.iter_mut() {
+        text.sections[0].value = log.clone();
+    }
+    let input = if chat_box.typing {
+        format!("> {}_", chat_box.input)
+    } else {
+        String::new()
+    };
+    for mut text in input_q.iter_mut() {
+        text.sections[0].value = input.clone();
+    }
+}
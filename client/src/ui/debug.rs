@@ -1,10 +1,13 @@
-use bevy::prelude::*;
+use bevy::{prelude::*, utils::HashSet};
 use common::{
+    conditioner::LinkConditioner,
+    message::FromServer,
     rollback::{InputRollback, SyncFrameCount},
-    Player, PlayerId, UMFromServer,
+    stats::NetworkStats,
+    IdPlayerInputs, Player, PlayerId,
 };
 
-use crate::{messages::ServerMessages, LocalPlayer};
+use crate::{input::InputStats, LocalPlayer};
 
 use super::UIRoot;
 
@@ -17,6 +20,17 @@ pub struct InputCounter {
 #[derive(Component)]
 pub struct SyncFrameCounter;
 
+#[derive(Component)]
+pub struct InputLossCounter;
+
+#[derive(Component)]
+pub struct NetworkStatsText;
+
+/// Default conditions of messages from the server, changed with the keys of
+/// `LinkConditions::adjust`.
+#[derive(Component)]
+pub struct LinkConditionsText;
+
 pub fn spawn_input_counters(
     mut commands: Commands,
     ui: Query<Entity, With<UIRoot>>,
@@ -41,24 +55,32 @@ pub fn spawn_input_counters(
     }
 }
 
+/// Input counters are spawned again with the players on the next login.
+pub fn despawn_input_counters(
+    mut commands: Commands,
+    counter_q: Query<Entity, With<InputCounter>>,
+) {
+    for entity in counter_q.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+}
+
 pub fn update_input_counters(
     rollback: Res<InputRollback>,
-    messages: Res<ServerMessages>,
+    mut id_player_inputs: EventReader<FromServer<IdPlayerInputs>>,
     local_player: Res<LocalPlayer>,
     mut text_q: Query<(&mut Text, &mut InputCounter)>,
 ) {
+    let remote_players = id_player_inputs
+        .read()
+        .flat_map(|FromServer { message }| message.0.iter().map(|input| input.player_id))
+        .collect::<HashSet<_>>();
     for (mut text, mut input_counter) in text_q.iter_mut() {
         let local_input = input_counter.player_id == local_player.id
             && rollback
                 .get_latest()
                 .is_some_and(|x| x.contains_key(&local_player.id));
-        let remote_input = messages.unreliable.iter().any(|msg| {
-            if let UMFromServer::IdPlayerInput(input) = msg {
-                input.player_id == input_counter.player_id
-            } else {
-                false
-            }
-        });
+        let remote_input = remote_players.contains(&input_counter.player_id);
         if local_input || remote_input {
             input_counter.count += 1;
             if local_input {
@@ -84,3 +106,53 @@ pub fn update_frame_counter(
         text.sections[0].value = format!("Frame: {}", frame.count());
     }
 }
+
+pub fn update_input_loss_counter(
+    input_stats: Res<InputStats>,
+    mut text_q: Query<&mut Text, With<InputLossCounter>>,
+) {
+    let acked_frame = input_stats
+        .acked_frame
+        .map_or_else(|| "-".to_string(), |frame| frame.to_string());
+    for mut text in text_q.iter_mut() {
+        text.sections[0].value = format!(
+            "Input loss: {}/{} (acked frame {})",
+            input_stats.lost, input_stats.sent, acked_frame
+        );
+    }
+}
+
+pub fn update_network_stats_text(
+    stats: Res<NetworkStats>,
+    mut text_q: Query<&mut Text, With<NetworkStatsText>>,
+) {
+    let server_loss = stats.server_link.map_or_else(
+        || "-".to_string(),
+        |link| format!("{:.1}%", link.packet_loss * 100.0),
+    );
+    for mut text in text_q.iter_mut() {
+        text.sections[0].value = format!(
+            "RTT: {:.0}ms (jitter {:.0}ms), loss: {:.1}% (server {}), in: {:.0}B/s, out: {:.0}B/s",
+            stats.link.rtt * 1000.0,
+            stats.link.jitter * 1000.0,
+            stats.link.packet_loss * 100.0,
+            server_loss,
+            stats.link.bytes_received_per_second,
+            stats.link.bytes_sent_per_second,
+        );
+    }
+}
+
+pub fn adjust_link_conditions(
+    keys: Res<Input<KeyCode>>,
+    mut conditioner: ResMut<LinkConditioner>,
+    mut text_q: Query<&mut Text, With<LinkConditionsText>>,
+) {
+    let conditions = &mut conditioner.server_to_client.default;
+    if conditions.adjust(&keys) {
+        info!("Conditions of messages from the server: {}", conditions);
+    }
+    for mut text in text_q.iter_mut() {
+        text.sections[0].value = format!("From server (F1-F10): {}", conditions);
+    }
+}
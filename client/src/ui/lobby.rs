@@ -0,0 +1,172 @@
+use bevy::prelude::*;
+use common::{
+    channel::NetClient, message::FromServer, LobbyRejected, LobbyRequest, LobbyState, ROMFromClient,
+};
+
+use crate::LocalPlayer;
+
+/// Keys that join the rooms listed first.
+const ROOM_KEYS: [KeyCode; 9] = [
+    KeyCode::Key1,
+    KeyCode::Key2,
+    KeyCode::Key3,
+    KeyCode::Key4,
+    KeyCode::Key5,
+    KeyCode::Key6,
+    KeyCode::Key7,
+    KeyCode::Key8,
+    KeyCode::Key9,
+];
+
+/// Rooms last sent by the server, and why the last request was rejected.
+#[derive(Resource, Default)]
+pub struct LobbyView {
+    state: Option<LobbyState>,
+    notice: Option<String>,
+}
+
+#[derive(Component)]
+pub struct LobbyRoot;
+
+#[derive(Component)]
+pub struct LobbyText;
+
+pub fn setup_lobby(mut commands: Commands) {
+    commands
+        .spawn(LobbyRoot)
+        .insert(NodeBundle {
+            style: Style {
+                width: Val::Percent(100.0),
+                height: Val::Percent(100.0),
+                justify_content: JustifyContent::Center,
+                align_items: AlignItems::Center,
+                ..default()
+            },
+            ..default()
+        })
+        .with_children(|parent| {
+            parent.spawn(LobbyText).insert(TextBundle::from_section(
+                "Entering the lobby...",
+                TextStyle {
+                    font_size: 24.0,
+                    ..Default::default()
+                },
+            ));
+        });
+}
+
+/// Removes the lobby screen and forgets the rooms, which are sent again on the next login.
+pub fn cleanup_lobby(
+    mut commands: Commands,
+    mut view: ResMut<LobbyView>,
+    lobby_q: Query<Entity, With<LobbyRoot>>,
+) {
+    for entity in lobby_q.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+    *view = LobbyView::default();
+}
+
+pub fn receive_lobby(
+    mut lobby_states: EventReader<FromServer<LobbyState>>,
+    mut lobby_rejections: EventReader<FromServer<LobbyRejected>>,
+    mut view: ResMut<LobbyView>,
+) {
+    if let Some(FromServer { message }) = lobby_states.read().last() {
+        view.state = Some(message.clone());
+    }
+    for FromServer {
+        message: LobbyRejected { reason },
+    } in lobby_rejections.read()
+    {
+        warn!("Lobby request rejected: {}", reason);
+        view.notice = Some(format!("{}.", reason));
+    }
+}
+
+/// C creates a room, 1 to 9 join a listed room, L leaves the room and R toggles ready.
+pub fn lobby_input(
+    keyboard_input: Res<Input<KeyCode>>,
+    mut client: NetClient,
+    mut view: ResMut<LobbyView>,
+    local_player: Res<LocalPlayer>,
+) {
+    let Some(state) = &view.state else {
+        return;
+    };
+    let mut request = None;
+    if keyboard_input.just_pressed(KeyCode::C) {
+        request = Some(LobbyRequest::CreateRoom {
+            name: format!("Room of player {}", local_player.id),
+        });
+    }
+    for (room, key) in state.rooms.iter().zip(ROOM_KEYS) {
+        if keyboard_input.just_pressed(key) {
+            request = Some(LobbyRequest::JoinRoom(room.id));
+        }
+    }
+    if keyboard_input.just_pressed(KeyCode::L) {
+        request = Some(LobbyRequest::LeaveRoom);
+    }
+    if keyboard_input.just_pressed(KeyCode::R) {
+        let ready = state
+            .rooms
+            .iter()
+            .flat_map(|room| room.players.iter())
+            .any(|(player_id, ready)| *player_id == local_player.id && *ready);
+        request = Some(LobbyRequest::SetReady(!ready));
+    }
+    if let Some(request) = request {
+        view.notice = None;
+        client.send(ROMFromClient::Lobby(request));
+    }
+}
+
+pub fn update_lobby_text(view: Res<LobbyView>, mut text_q: Query<&mut Text, With<LobbyText>>) {
+    if !view.is_changed() {
+        return;
+    }
+    let Some(state) = &view.state else {
+        return;
+    };
+    let mut lines = vec![format!(
+        "Lobby - a room starts once {} players are ready",
+        state.min_match_players
+    )];
+    if state.rooms.is_empty() {
+        lines.push("No rooms yet.".to_string());
+    }
+    for (index, room) in state.rooms.iter().enumerate() {
+        let players = room
+            .players
+            .iter()
+            .map(|(player_id, ready)| {
+                format!("{}{}", player_id, if *ready { " (ready)" } else { "" })
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        let marker = if state.room == Some(room.id) {
+            ">"
+        } else {
+            " "
+        };
+        lines.push(format!(
+            "{} {}. {}: {}",
+            marker,
+            index + 1,
+            room.name,
+            players
+        ));
+    }
+    lines.push(String::new());
+    lines.push(match state.room {
+        Some(_) => "R: toggle ready, L: leave room, C: create a room".to_string(),
+        None => "C: create a room, 1-9: join a room".to_string(),
+    });
+    if let Some(notice) = &view.notice {
+        lines.push(notice.clone());
+    }
+    for mut text in text_q.iter_mut() {
+        text.sections[0].value = lines.join("\n");
+    }
+}
@@ -0,0 +1,55 @@
+use bevy::prelude::*;
+
+/// Message shown in the main menu, e.g. why logging in failed.
+#[derive(Resource, Default)]
+pub struct MenuStatus(pub Option<String>);
+
+#[derive(Component)]
+pub struct MenuRoot;
+
+#[derive(Component)]
+pub struct MenuStatusText;
+
+pub fn setup_menu(mut commands: Commands) {
+    commands
+        .spawn(MenuRoot)
+        .insert(NodeBundle {
+            style: Style {
+                width: Val::Percent(100.0),
+                height: Val::Percent(100.0),
+                justify_content: JustifyContent::Center,
+                align_items: AlignItems::Center,
+                ..default()
+            },
+            ..default()
+        })
+        .with_children(|parent| {
+            parent
+                .spawn(MenuStatusText)
+                .insert(TextBundle::from_section(
+                    "Connecting...",
+                    TextStyle {
+                        font_size: 24.0,
+                        ..Default::default()
+                    },
+                ));
+        });
+}
+
+pub fn cleanup_menu(mut commands: Commands, menu_q: Query<Entity, With<MenuRoot>>) {
+    for entity in menu_q.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+}
+
+pub fn update_menu_status(
+    status: Res<MenuStatus>,
+    mut text_q: Query<&mut Text, With<MenuStatusText>>,
+) {
+    let Some(message) = status.0.as_ref() else {
+        return;
+    };
+    for mut text in text_q.iter_mut() {
+        text.sections[0].value = message.clone();
+    }
+}
@@ -1,13 +1,21 @@
 use bevy::prelude::*;
 use common::schedule::{ClientSchedule, ClientState};
 
-use self::debug::SyncFrameCounter;
+use crate::events::ServerShutdown;
 
+use self::debug::{InputLossCounter, LinkConditionsText, NetworkStatsText, SyncFrameCounter};
+
+pub mod chat;
 mod debug;
+mod lobby;
+pub mod menu;
 
 #[derive(Component)]
 pub struct UIRoot;
 
+#[derive(Component)]
+pub struct ShutdownText;
+
 pub fn setup_ui(mut commands: Commands) {
     commands
         .spawn(UIRoot)
@@ -21,6 +29,13 @@ pub fn setup_ui(mut commands: Commands) {
             ..default()
         })
         .with_children(|parent| {
+            parent.spawn(ShutdownText).insert(TextBundle::from_section(
+                "",
+                TextStyle {
+                    font_size: 24.0,
+                    ..Default::default()
+                },
+            ));
             parent
                 .spawn(SyncFrameCounter)
                 .insert(TextBundle::from_section(
@@ -30,23 +45,102 @@ pub fn setup_ui(mut commands: Commands) {
                         ..Default::default()
                     },
                 ));
+            parent
+                .spawn(InputLossCounter)
+                .insert(TextBundle::from_section(
+                    "Input loss: -",
+                    TextStyle {
+                        font_size: 20.0,
+                        ..Default::default()
+                    },
+                ));
+            parent
+                .spawn(NetworkStatsText)
+                .insert(TextBundle::from_section(
+                    "RTT: -",
+                    TextStyle {
+                        font_size: 20.0,
+                        ..Default::default()
+                    },
+                ));
+            parent
+                .spawn(LinkConditionsText)
+                .insert(TextBundle::from_section(
+                    "",
+                    TextStyle {
+                        font_size: 20.0,
+                        ..Default::default()
+                    },
+                ));
         });
+    commands.spawn(Camera2dBundle::default());
+}
+
+pub fn update_shutdown_text(
+    shutdown: Option<Res<ServerShutdown>>,
+    mut text_q: Query<&mut Text, With<ShutdownText>>,
+) {
+    let message = shutdown.map_or_else(String::new, |shutdown| {
+        format!("Server shutting down in {}s", shutdown.seconds_left)
+    });
+    for mut text in text_q.iter_mut() {
+        text.sections[0].value = message.clone();
+    }
 }
 
 pub struct UIPlugin;
 
 impl Plugin for UIPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(Startup, setup_ui).add_systems(
-            FixedUpdate,
-            (
-                debug::spawn_input_counters,
-                debug::update_input_counters,
-                debug::update_frame_counter,
+        app.init_resource::<menu::MenuStatus>()
+            .init_resource::<chat::ChatBox>()
+            .init_resource::<lobby::LobbyView>()
+            .add_systems(Startup, (setup_ui, chat::setup_chat))
+            .add_systems(OnEnter(ClientState::MainMenu), menu::setup_menu)
+            .add_systems(OnExit(ClientState::MainMenu), menu::cleanup_menu)
+            .add_systems(OnEnter(ClientState::Lobby), lobby::setup_lobby)
+            .add_systems(OnExit(ClientState::Lobby), lobby::cleanup_lobby)
+            .add_systems(OnEnter(ClientState::Reconnecting), menu::setup_menu)
+            .add_systems(
+                OnExit(ClientState::InGame),
+                (debug::despawn_input_counters, chat::close_chat_box),
+            )
+            .add_systems(OnExit(ClientState::Reconnecting), menu::cleanup_menu)
+            .add_systems(
+                Update,
+                (
+                    menu::update_menu_status.run_if(
+                        in_state(ClientState::MainMenu)
+                            .or_else(in_state(ClientState::Reconnecting)),
+                    ),
+                    (lobby::lobby_input, lobby::update_lobby_text)
+                        .run_if(in_state(ClientState::Lobby)),
+                    update_shutdown_text,
+                    debug::adjust_link_conditions,
+                    chat::type_chat.run_if(in_state(ClientState::InGame)),
+                    chat::update_chat_text,
+                ),
+            )
+            .add_systems(
+                FixedUpdate,
+                (
+                    chat::receive_chat.run_if(in_state(ClientState::InGame)),
+                    lobby::receive_lobby.run_if(in_state(ClientState::Lobby)),
+                )
+                    .in_set(ClientSchedule::ServerEventHandling),
             )
-                .chain()
-                .in_set(ClientSchedule::ServerReactive)
-                .run_if(in_state(ClientState::InGame)),
-        );
+            .add_systems(
+                FixedUpdate,
+                (
+                    debug::spawn_input_counters,
+                    debug::update_input_counters,
+                    debug::update_frame_counter,
+                    debug::update_input_loss_counter,
+                    debug::update_network_stats_text,
+                )
+                    .chain()
+                    .in_set(ClientSchedule::ServerReactive)
+                    .run_if(in_state(ClientState::InGame)),
+            );
     }
 }
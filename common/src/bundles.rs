@@ -1,11 +1,15 @@
 use bevy::prelude::*;
 use serde::{Deserialize, Serialize};
 
-use crate::Player;
+use crate::{quantize, Player};
 
 /// Probs will have collider & stuff later.
 #[derive(Bundle, Copy, Clone, Serialize, Deserialize, Debug)]
 pub struct PlayerData {
     pub player: Player,
+    #[serde(
+        serialize_with = "quantize::serialize_transform",
+        deserialize_with = "quantize::deserialize_transform"
+    )]
     pub transform: Transform,
 }
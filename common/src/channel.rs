@@ -0,0 +1,216 @@
+use std::{ops::Deref, time::Duration};
+
+use bevy::{ecs::system::SystemParam, log::error, prelude::*};
+use bevy_renet::renet::{
+    ChannelConfig, ClientId, ConnectionConfig, RenetClient, RenetServer, SendType,
+};
+
+use crate::{message::NetMessage, stats::NetworkStats};
+
+/// Channels the server sends on. Earlier channels get priority when packets are generated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServerChannel {
+    /// Relayed inputs and input acknowledgements.
+    Input,
+    /// Full and delta game syncs.
+    Snapshot,
+    /// Game and connection events that must arrive in order.
+    Events,
+    Chat,
+}
+
+/// Channels the client sends on. Earlier channels get priority when packets are generated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientChannel {
+    /// Inputs and game sync acknowledgements.
+    Input,
+    /// Requests that must arrive in order.
+    Events,
+    Chat,
+}
+
+impl From<ServerChannel> for u8 {
+    fn from(channel: ServerChannel) -> Self {
+        match channel {
+            ServerChannel::Input => 0,
+            ServerChannel::Snapshot => 1,
+            ServerChannel::Events => 2,
+            ServerChannel::Chat => 3,
+        }
+    }
+}
+
+impl From<ClientChannel> for u8 {
+    fn from(channel: ClientChannel) -> Self {
+        match channel {
+            ClientChannel::Input => 0,
+            ClientChannel::Events => 1,
+            ClientChannel::Chat => 2,
+        }
+    }
+}
+
+impl ServerChannel {
+    pub const ALL: [Self; 4] = [Self::Input, Self::Snapshot, Self::Events, Self::Chat];
+
+    pub fn is_reliable(self) -> bool {
+        matches!(self, Self::Events | Self::Chat)
+    }
+
+    fn config() -> Vec<ChannelConfig> {
+        vec![
+            ChannelConfig {
+                channel_id: Self::Input.into(),
+                max_memory_usage_bytes: 1024 * 1024,
+                send_type: SendType::Unreliable,
+            },
+            ChannelConfig {
+                channel_id: Self::Snapshot.into(),
+                max_memory_usage_bytes: 5 * 1024 * 1024,
+                send_type: SendType::Unreliable,
+            },
+            ChannelConfig {
+                channel_id: Self::Events.into(),
+                max_memory_usage_bytes: 5 * 1024 * 1024,
+                send_type: SendType::ReliableOrdered {
+                    resend_time: Duration::from_millis(200),
+                },
+            },
+            ChannelConfig {
+                channel_id: Self::Chat.into(),
+                max_memory_usage_bytes: 1024 * 1024,
+                send_type: SendType::ReliableOrdered {
+                    resend_time: Duration::from_millis(500),
+                },
+            },
+        ]
+    }
+}
+
+impl ClientChannel {
+    pub const ALL: [Self; 3] = [Self::Input, Self::Events, Self::Chat];
+
+    pub fn is_reliable(self) -> bool {
+        matches!(self, Self::Events | Self::Chat)
+    }
+
+    fn config() -> Vec<ChannelConfig> {
+        vec![
+            ChannelConfig {
+                channel_id: Self::Input.into(),
+                max_memory_usage_bytes: 1024 * 1024,
+                send_type: SendType::Unreliable,
+            },
+            ChannelConfig {
+                channel_id: Self::Events.into(),
+                max_memory_usage_bytes: 1024 * 1024,
+                send_type: SendType::ReliableOrdered {
+                    resend_time: Duration::from_millis(200),
+                },
+            },
+            ChannelConfig {
+                channel_id: Self::Chat.into(),
+                max_memory_usage_bytes: 256 * 1024,
+                send_type: SendType::ReliableOrdered {
+                    resend_time: Duration::from_millis(500),
+                },
+            },
+        ]
+    }
+}
+
+/// Connection config shared by the server and client, both must use the same channels.
+pub fn connection_config() -> ConnectionConfig {
+    ConnectionConfig {
+        available_bytes_per_tick: 60_000,
+        server_channels_config: ServerChannel::config(),
+        client_channels_config: ClientChannel::config(),
+    }
+}
+
+/// The renet server, sending messages on the channel their type is bound to and counting them
+/// in `NetworkStats`. Messages are received through the `LinkConditioner`, see `conditioner`.
+#[derive(SystemParam)]
+pub struct NetServer<'w> {
+    server: ResMut<'w, RenetServer>,
+    stats: ResMut<'w, NetworkStats>,
+}
+
+/// Read only, sending goes through `send` and `broadcast` to use the message's channel.
+impl Deref for NetServer<'_> {
+    type Target = RenetServer;
+
+    fn deref(&self) -> &RenetServer {
+        &self.server
+    }
+}
+
+impl NetServer<'_> {
+    pub fn stats_mut(&mut self) -> &mut NetworkStats {
+        &mut self.stats
+    }
+
+    pub fn send<M: NetMessage<Channel = ServerChannel>>(
+        &mut self,
+        client_id: ClientId,
+        message: M,
+    ) {
+        match message.encode() {
+            Ok(bytes) => {
+                self.stats.count_sent(M::CHANNEL.into(), bytes.len());
+                self.server.send_message(client_id, M::CHANNEL, bytes)
+            }
+            Err(err) => error!("Failed to encode message for client {}: {}", client_id, err),
+        }
+    }
+
+    pub fn broadcast<M: NetMessage<Channel = ServerChannel>>(&mut self, message: M) {
+        match message.encode() {
+            Ok(bytes) => {
+                let clients = self.server.clients_id().len();
+                self.stats
+                    .count_sent(M::CHANNEL.into(), bytes.len() * clients);
+                self.server.broadcast_message(M::CHANNEL, bytes)
+            }
+            Err(err) => error!("Failed to encode broadcast message: {}", err),
+        }
+    }
+}
+
+/// The renet client, sending messages on the channel their type is bound to and counting them
+/// in `NetworkStats`.
+#[derive(SystemParam)]
+pub struct NetClient<'w> {
+    client: ResMut<'w, RenetClient>,
+    stats: ResMut<'w, NetworkStats>,
+}
+
+/// Read only, sending goes through `send` to use the message's channel.
+impl Deref for NetClient<'_> {
+    type Target = RenetClient;
+
+    fn deref(&self) -> &RenetClient {
+        &self.client
+    }
+}
+
+impl NetClient<'_> {
+    pub fn send<M: NetMessage<Channel = ClientChannel>>(&mut self, message: M) {
+        send_to_server(&mut self.client, &mut self.stats, message);
+    }
+}
+
+/// Sends a message from the client outside of a system, see `NetClient`.
+pub fn send_to_server<M: NetMessage<Channel = ClientChannel>>(
+    client: &mut RenetClient,
+    stats: &mut NetworkStats,
+    message: M,
+) {
+    match message.encode() {
+        Ok(bytes) => {
+            stats.count_sent(M::CHANNEL.into(), bytes.len());
+            client.send_message(M::CHANNEL, bytes)
+        }
+        Err(err) => error!("Failed to encode message for server: {}", err),
+    }
+}
@@ -0,0 +1,218 @@
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+use bevy::{prelude::*, utils::HashMap};
+use bevy_renet::renet::Bytes;
+use rand::Rng;
+
+use crate::{
+    channel::{ClientChannel, ServerChannel},
+    message::Direction,
+};
+
+/// Simulated network conditions of a channel.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LinkConditions {
+    /// Delay added to every message.
+    pub latency: Duration,
+    /// Largest random delay added on top of `latency`.
+    pub jitter: Duration,
+    /// Chance from 0 to 1 that a message is dropped.
+    pub loss: f32,
+    /// Chance from 0 to 1 that a message is delivered twice.
+    pub duplication: f32,
+    /// Chance from 0 to 1 that a message is held back for an extra `latency` plus `jitter`,
+    /// letting later messages overtake it.
+    pub reordering: f32,
+}
+
+impl LinkConditions {
+    fn delay(&self, rng: &mut impl Rng) -> Duration {
+        self.latency + self.jitter.mul_f32(rng.gen_range(0.0..=1.0))
+    }
+
+    /// Changes the conditions with debug keys: F1 and F2 lower and raise the latency, F3 and F4
+    /// the jitter, F5 and F6 the loss, F7 and F8 the duplication and F9 and F10 the reordering.
+    /// Returns whether anything changed.
+    pub fn adjust(&mut self, keys: &Input<KeyCode>) -> bool {
+        let step = |lower: KeyCode, raise: KeyCode| {
+            keys.just_pressed(raise) as i32 - keys.just_pressed(lower) as i32
+        };
+        let before = *self;
+        let latency = step(KeyCode::F1, KeyCode::F2);
+        let jitter = step(KeyCode::F3, KeyCode::F4);
+        self.latency = adjust_duration(self.latency, latency, Duration::from_millis(25));
+        self.jitter = adjust_duration(self.jitter, jitter, Duration::from_millis(10));
+        self.loss = adjust_chance(self.loss, step(KeyCode::F5, KeyCode::F6));
+        self.duplication = adjust_chance(self.duplication, step(KeyCode::F7, KeyCode::F8));
+        self.reordering = adjust_chance(self.reordering, step(KeyCode::F9, KeyCode::F10));
+        *self != before
+    }
+}
+
+fn adjust_duration(duration: Duration, steps: i32, step: Duration) -> Duration {
+    if steps < 0 {
+        duration.saturating_sub(step)
+    } else {
+        duration + step * steps as u32
+    }
+}
+
+fn adjust_chance(chance: f32, steps: i32) -> f32 {
+    (chance + steps as f32 * 0.05).clamp(0.0, 1.0)
+}
+
+impl fmt::Display for LinkConditions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "latency {}ms (jitter {}ms), loss {:.0}%, duplication {:.0}%, reordering {:.0}%",
+            self.latency.as_millis(),
+            self.jitter.as_millis(),
+            self.loss * 100.0,
+            self.duplication * 100.0,
+            self.reordering * 100.0
+        )
+    }
+}
+
+/// Conditions of every channel in one direction.
+#[derive(Debug, Clone, Default)]
+pub struct DirectionConditions {
+    /// Conditions of channels without their own.
+    pub default: LinkConditions,
+    pub channels: HashMap<u8, LinkConditions>,
+}
+
+impl DirectionConditions {
+    pub fn get(&self, channel: u8) -> &LinkConditions {
+        self.channels.get(&channel).unwrap_or(&self.default)
+    }
+}
+
+/// Simulated network conditions, applied by each side to the messages it receives. Loss,
+/// duplication and reordering only apply to unreliable channels, messages on reliable
+/// channels are only delayed and keep their order.
+///
+/// Can be changed at runtime, changes apply to messages received afterwards.
+#[derive(Resource, Debug, Clone, Default)]
+pub struct LinkConditioner {
+    pub server_to_client: DirectionConditions,
+    pub client_to_server: DirectionConditions,
+}
+
+impl LinkConditioner {
+    pub fn direction(&self, direction: Direction) -> &DirectionConditions {
+        match direction {
+            Direction::ServerToClient => &self.server_to_client,
+            Direction::ClientToServer => &self.client_to_server,
+        }
+    }
+
+    pub fn set_server_channel(&mut self, channel: ServerChannel, conditions: LinkConditions) {
+        self.server_to_client
+            .channels
+            .insert(channel.into(), conditions);
+    }
+
+    pub fn set_client_channel(&mut self, channel: ClientChannel, conditions: LinkConditions) {
+        self.client_to_server
+            .channels
+            .insert(channel.into(), conditions);
+    }
+}
+
+struct DelayedMessage<K> {
+    deliver_at: Instant,
+    sender: K,
+    channel: u8,
+    bytes: Bytes,
+}
+
+/// Received messages held back until their simulated delivery time, keyed by sender.
+#[derive(Resource)]
+pub struct ConditionedMessages<K> {
+    messages: Vec<DelayedMessage<K>>,
+    /// Latest delivery time on each reliable channel, so that reliable messages keep their order.
+    last_reliable: HashMap<(K, u8), Instant>,
+}
+
+impl<K> Default for ConditionedMessages<K> {
+    fn default() -> Self {
+        Self {
+            messages: Vec::new(),
+            last_reliable: HashMap::default(),
+        }
+    }
+}
+
+impl<K: Copy + Eq + std::hash::Hash> ConditionedMessages<K> {
+    pub fn push(
+        &mut self,
+        conditions: &LinkConditions,
+        reliable: bool,
+        sender: K,
+        channel: u8,
+        bytes: Bytes,
+        now: Instant,
+    ) {
+        let mut rng = rand::thread_rng();
+        if reliable {
+            let last = self.last_reliable.entry((sender, channel)).or_insert(now);
+            *last = (now + conditions.delay(&mut rng)).max(*last);
+            self.messages.push(DelayedMessage {
+                deliver_at: *last,
+                sender,
+                channel,
+                bytes,
+            });
+            return;
+        }
+
+        if rng.gen::<f32>() < conditions.loss {
+            return;
+        }
+        let copies = if rng.gen::<f32>() < conditions.duplication {
+            2
+        } else {
+            1
+        };
+        for _ in 0..copies {
+            let mut deliver_at = now + conditions.delay(&mut rng);
+            if rng.gen::<f32>() < conditions.reordering {
+                deliver_at += conditions.delay(&mut rng);
+            }
+            self.messages.push(DelayedMessage {
+                deliver_at,
+                sender,
+                channel,
+                bytes: bytes.clone(),
+            });
+        }
+    }
+
+    /// Takes the messages on `channel` due by `now`, in the order they are delivered.
+    pub fn deliver(&mut self, channel: u8, now: Instant) -> Vec<(K, Bytes)> {
+        let mut due = Vec::new();
+        self.messages.retain(|message| {
+            if message.channel == channel && message.deliver_at <= now {
+                due.push((message.deliver_at, message.sender, message.bytes.clone()));
+                false
+            } else {
+                true
+            }
+        });
+        due.sort_by_key(|(deliver_at, _, _)| *deliver_at);
+        due.into_iter()
+            .map(|(_, sender, bytes)| (sender, bytes))
+            .collect()
+    }
+
+    /// Drops messages from a sender that disconnected.
+    pub fn remove_sender(&mut self, sender: &K) {
+        self.messages.retain(|message| message.sender != *sender);
+        self.last_reliable.retain(|(key, _), _| key != sender);
+    }
+}
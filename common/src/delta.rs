@@ -0,0 +1,314 @@
+use std::collections::VecDeque;
+
+use bevy::{
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    quantize::{NetTransform, TRANSFORM_PRECISION},
+    GameSync, Player, PlayerId, ServerObject,
+};
+
+/// Number of game syncs kept as possible delta baselines.
+pub const SYNC_HISTORY_LENGTH: usize = 32;
+
+/// A value that can be encoded as the fields that changed against a baseline.
+pub trait Delta: Default + Sized {
+    type Delta: Default;
+
+    /// Returns `None` if nothing changed.
+    fn diff(&self, baseline: &Self) -> Option<Self::Delta>;
+
+    fn apply(baseline: &Self, delta: &Self::Delta) -> Self;
+}
+
+/// Changed fields of the quantized `NetTransform`, so that deltas are as compact as full syncs
+/// and are applied to the same values the client decoded.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TransformDelta {
+    pub position: Option<[i32; 2]>,
+    pub angle: Option<u16>,
+    /// `Some(None)` resets the scale to one.
+    pub scale: Option<Option<[i16; 2]>>,
+}
+
+impl Delta for Transform {
+    type Delta = TransformDelta;
+
+    fn diff(&self, baseline: &Self) -> Option<TransformDelta> {
+        let net = NetTransform::quantize(self, &TRANSFORM_PRECISION);
+        let baseline = NetTransform::quantize(baseline, &TRANSFORM_PRECISION);
+        let delta = TransformDelta {
+            position: changed(net.position, baseline.position),
+            angle: changed(net.angle, baseline.angle),
+            scale: changed(net.scale, baseline.scale),
+        };
+        (delta.position.is_some() || delta.angle.is_some() || delta.scale.is_some())
+            .then_some(delta)
+    }
+
+    fn apply(baseline: &Self, delta: &TransformDelta) -> Self {
+        let baseline = NetTransform::quantize(baseline, &TRANSFORM_PRECISION);
+        NetTransform {
+            position: delta.position.unwrap_or(baseline.position),
+            angle: delta.angle.unwrap_or(baseline.angle),
+            scale: delta.scale.unwrap_or(baseline.scale),
+        }
+        .to_transform(&TRANSFORM_PRECISION)
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PlayerDelta {
+    pub id: Option<PlayerId>,
+    pub speed: Option<f32>,
+}
+
+impl Delta for Player {
+    type Delta = PlayerDelta;
+
+    fn diff(&self, baseline: &Self) -> Option<PlayerDelta> {
+        let delta = PlayerDelta {
+            id: changed(self.id, baseline.id),
+            speed: changed(self.speed, baseline.speed),
+        };
+        (delta.id.is_some() || delta.speed.is_some()).then_some(delta)
+    }
+
+    fn apply(baseline: &Self, delta: &PlayerDelta) -> Self {
+        Self {
+            id: delta.id.unwrap_or(baseline.id),
+            speed: delta.speed.unwrap_or(baseline.speed),
+        }
+    }
+}
+
+fn changed<T: PartialEq>(value: T, baseline: T) -> Option<T> {
+    (value != baseline).then_some(value)
+}
+
+/// Changes of the server objects in a `GameSync` against a baseline the client has
+/// acknowledged. The baseline is the client's view of every object it was synced, each with the
+/// value it was last sent. Server objects missing from the baseline are diffed against the
+/// default value.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GameSyncDelta {
+    pub frame: u64,
+    pub baseline_frame: u64,
+    /// Unix time this sync was generated in seconds.
+    pub unix_time: f64,
+    pub transforms: HashMap<ServerObject, TransformDelta>,
+    pub players: HashMap<ServerObject, PlayerDelta>,
+    /// Server objects in the baseline that left the client's area of interest.
+    pub removed: Vec<ServerObject>,
+}
+
+impl GameSyncDelta {
+    /// Encodes every server object of `game_sync`, even unchanged ones, against `baseline`.
+    /// Objects of the baseline for which `keep` is false are removed.
+    pub fn between(
+        baseline: &GameSync,
+        game_sync: &GameSync,
+        keep: impl Fn(&ServerObject) -> bool,
+    ) -> Self {
+        let removed = baseline
+            .transforms
+            .keys()
+            .chain(baseline.players.keys())
+            .filter(|server_obj| !keep(server_obj))
+            .copied()
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+
+        Self {
+            frame: game_sync.frame,
+            baseline_frame: baseline.frame,
+            unix_time: game_sync.unix_time,
+            transforms: diff_map(&baseline.transforms, &game_sync.transforms),
+            players: diff_map(&baseline.players, &game_sync.players),
+            removed,
+        }
+    }
+
+    /// Whether the server object was synced by this delta.
+    pub fn contains(&self, server_obj: &ServerObject) -> bool {
+        self.transforms.contains_key(server_obj) || self.players.contains_key(server_obj)
+    }
+
+    /// Rebuilds the client's view from the baseline it was encoded against. Only the objects
+    /// this delta `contains` hold values of its frame.
+    pub fn apply(&self, baseline: &GameSync) -> GameSync {
+        debug_assert_eq!(baseline.frame, self.baseline_frame);
+        let mut game_sync = GameSync {
+            frame: self.frame,
+            unix_time: self.unix_time,
+            transforms: apply_map(&baseline.transforms, &self.transforms),
+            players: apply_map(&baseline.players, &self.players),
+        };
+        for server_obj in self.removed.iter() {
+            game_sync.transforms.remove(server_obj);
+            game_sync.players.remove(server_obj);
+        }
+        game_sync
+    }
+}
+
+fn diff_map<T: Delta>(
+    baseline: &HashMap<ServerObject, T>,
+    values: &HashMap<ServerObject, T>,
+) -> HashMap<ServerObject, T::Delta> {
+    let default = T::default();
+    values
+        .iter()
+        .map(|(server_obj, value)| {
+            let baseline_value = baseline.get(server_obj).unwrap_or(&default);
+            (*server_obj, value.diff(baseline_value).unwrap_or_default())
+        })
+        .collect()
+}
+
+fn apply_map<T: Delta + Clone>(
+    baseline: &HashMap<ServerObject, T>,
+    deltas: &HashMap<ServerObject, T::Delta>,
+) -> HashMap<ServerObject, T> {
+    let default = T::default();
+    let mut values = baseline.clone();
+    for (server_obj, delta) in deltas.iter() {
+        let baseline_value = baseline.get(server_obj).unwrap_or(&default);
+        values.insert(*server_obj, T::apply(baseline_value, delta));
+    }
+    values
+}
+
+/// Recent views of the client's world, as sent in full syncs or rebuilt from deltas, used as
+/// baselines for deltas.
+#[derive(Resource, Default)]
+pub struct SyncHistory(VecDeque<GameSync>);
+
+impl SyncHistory {
+    pub fn push(&mut self, game_sync: GameSync) {
+        self.0.retain(|sync| sync.frame != game_sync.frame);
+        self.0.push_back(game_sync);
+        if self.0.len() > SYNC_HISTORY_LENGTH {
+            self.0.pop_front();
+        }
+    }
+
+    pub fn get(&self, frame: u64) -> Option<&GameSync> {
+        self.0.iter().find(|sync| sync.frame == frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server_object(index: u32) -> ServerObject {
+        ServerObject::new(index, 0)
+    }
+
+    fn game_sync(frame: u64, objects: &[(u32, Vec3)]) -> GameSync {
+        GameSync {
+            frame,
+            unix_time: frame as f64,
+            transforms: objects
+                .iter()
+                .map(|(index, position)| {
+                    (
+                        server_object(*index),
+                        Transform::from_translation(*position),
+                    )
+                })
+                .collect(),
+            players: objects
+                .iter()
+                .map(|(index, _)| (server_object(*index), Player::new(PlayerId(*index as u64))))
+                .collect(),
+        }
+    }
+
+    fn player_ids(game_sync: &GameSync) -> HashMap<ServerObject, PlayerId> {
+        game_sync
+            .players
+            .iter()
+            .map(|(server_obj, player)| (*server_obj, player.id))
+            .collect()
+    }
+
+    fn net(transform: &Transform) -> NetTransform {
+        NetTransform::quantize(transform, &TRANSFORM_PRECISION)
+    }
+
+    #[test]
+    fn applied_delta_rebuilds_the_game_sync() {
+        let baseline = game_sync(1, &[(0, Vec3::ZERO), (1, Vec3::new(5.0, 5.0, 0.0))]);
+        let game_sync = game_sync(
+            2,
+            &[
+                (0, Vec3::new(1.25, -3.5, 0.0)),
+                (1, Vec3::new(5.0, 5.0, 0.0)),
+                (2, Vec3::X),
+            ],
+        );
+
+        let delta = GameSyncDelta::between(&baseline, &game_sync, |_| true);
+        assert!(delta.removed.is_empty());
+        let view = delta.apply(&baseline);
+
+        assert_eq!(view.frame, 2);
+        assert_eq!(player_ids(&view), player_ids(&game_sync));
+        assert_eq!(view.transforms.len(), game_sync.transforms.len());
+        for (server_obj, transform) in game_sync.transforms.iter() {
+            assert_eq!(net(&view.transforms[server_obj]), net(transform));
+        }
+    }
+
+    #[test]
+    fn removed_objects_leave_the_view() {
+        let baseline = game_sync(1, &[(0, Vec3::ZERO), (1, Vec3::X)]);
+        let game_sync = game_sync(2, &[(0, Vec3::Y)]);
+
+        let delta = GameSyncDelta::between(&baseline, &game_sync, |server_obj| {
+            *server_obj != server_object(1)
+        });
+        assert_eq!(delta.removed, vec![server_object(1)]);
+        let view = delta.apply(&baseline);
+
+        assert!(!view.transforms.contains_key(&server_object(1)));
+        assert!(!view.players.contains_key(&server_object(1)));
+        assert_eq!(
+            net(&view.transforms[&server_object(0)]),
+            net(&Transform::from_translation(Vec3::Y))
+        );
+    }
+
+    #[test]
+    fn objects_left_out_keep_their_baseline() {
+        let baseline = game_sync(1, &[(0, Vec3::ZERO), (1, Vec3::X)]);
+        let game_sync = game_sync(2, &[(0, Vec3::Y)]);
+
+        let delta = GameSyncDelta::between(&baseline, &game_sync, |_| true);
+        assert!(delta.contains(&server_object(0)));
+        assert!(!delta.contains(&server_object(1)));
+        let view = delta.apply(&baseline);
+
+        assert_eq!(
+            view.transforms[&server_object(1)],
+            baseline.transforms[&server_object(1)]
+        );
+        assert_eq!(player_ids(&view), player_ids(&baseline));
+    }
+
+    #[test]
+    fn unchanged_objects_are_still_synced() {
+        let baseline = game_sync(1, &[(0, Vec3::X)]);
+        let game_sync = game_sync(2, &[(0, Vec3::X)]);
+
+        let delta = GameSyncDelta::between(&baseline, &game_sync, |_| true);
+        assert!(delta.contains(&server_object(0)));
+        assert!(delta.transforms[&server_object(0)].position.is_none());
+    }
+}
@@ -9,7 +9,7 @@ use bevy_rapier2d::{
     render::RapierDebugRenderPlugin,
 };
 
-use crate::{rollback::InputFrame, Player, FRAME_DURATION_SECONDS};
+use crate::{rollback::InputFrame, Player};
 
 #[derive(Debug, Hash, PartialEq, Eq, Clone, SystemSet)]
 pub enum GameSet {
@@ -24,15 +24,17 @@ pub struct GameLogic;
 pub fn move_player(
     mut player_q: Query<(&Player, &mut KinematicCharacterController)>,
     input_frame: Res<InputFrame>,
+    time: Res<Time<Fixed>>,
 ) {
+    let frame_duration = time.timestep().as_secs_f32();
     for (player, mut controller) in player_q.iter_mut() {
         if let Some(input) = input_frame.get(&player.id) {
             if controller.translation.is_some() {
                 warn!("Overwriting translation for player {}", player.id);
             }
             controller.translation = Some(Vec2::new(
-                input.x_move as f32 * player.speed * FRAME_DURATION_SECONDS as f32,
-                input.y_move as f32 * player.speed * FRAME_DURATION_SECONDS as f32,
+                input.x_move as f32 * player.speed * frame_duration,
+                input.y_move as f32 * player.speed * frame_duration,
             ));
 
             if input.shoot {
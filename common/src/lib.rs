@@ -1,36 +1,55 @@
 use std::{any::{Any, TypeId}, time::SystemTime};
 
-use bevy::{prelude::*, utils::HashMap};
-use bevy_renet::renet::Bytes;
-use bundles::PlayerData;
+use bevy::{
+    prelude::*,
+    utils::{Duration, HashMap},
+};
+use delta::GameSyncDelta;
+use replication::ReplicatedComponent;
 use serde::{Deserialize, Serialize};
+use stats::LinkStats;
 
 pub mod bundles;
+pub mod channel;
+pub mod conditioner;
+pub mod delta;
 pub mod game;
+pub mod message;
+pub mod protocol;
+pub mod quantize;
+pub mod replication;
 pub mod rollback;
 pub mod schedule;
+pub mod stats;
 
+/// Duration of a frame unless the server is configured with another tick rate.
 pub const FRAME_DURATION_SECONDS: f64 = 1.0 / 5.0;
 
+/// Number of most recent inputs repeated in every input packet, so that a lost packet does
+/// not lose the input it carried.
+pub const INPUT_REDUNDANCY: usize = 8;
+
 pub fn fixed_timestep_rate() -> Time<Fixed> {
     Time::<Fixed>::from_seconds(FRAME_DURATION_SECONDS)
 }
 
-macro_rules! impl_bytes {
-    ($t:ty) => {
-        impl Into<Bytes> for $t {
-            fn into(self) -> Bytes {
-                let encoded = bincode::serialize(&self).unwrap();
-                Bytes::copy_from_slice(&encoded)
-            }
-        }
+macro_rules! impl_net_message {
+    ($t:ty, ServerChannel::$channel:ident, $max_size:expr) => {
+        impl message::NetMessage for $t {
+            type Channel = channel::ServerChannel;
 
-        impl TryFrom<Bytes> for $t {
-            type Error = bincode::Error;
+            const CHANNEL: channel::ServerChannel = channel::ServerChannel::$channel;
+            const DIRECTION: message::Direction = message::Direction::ServerToClient;
+            const MAX_SIZE: usize = $max_size;
+        }
+    };
+    ($t:ty, ClientChannel::$channel:ident, $max_size:expr) => {
+        impl message::NetMessage for $t {
+            type Channel = channel::ClientChannel;
 
-            fn try_from(bytes: Bytes) -> Result<Self, bincode::Error> {
-                bincode::deserialize(&bytes)
-            }
+            const CHANNEL: channel::ClientChannel = channel::ClientChannel::$channel;
+            const DIRECTION: message::Direction = message::Direction::ClientToServer;
+            const MAX_SIZE: usize = $max_size;
         }
     };
 }
@@ -68,6 +87,11 @@ impl std::fmt::Display for PlayerId {
     }
 }
 
+/// Secret issued to a client on login, which lets it resume its player after its connection
+/// drops.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Hash)]
+pub struct SessionToken(pub u64);
+
 #[derive(Component, Clone, Copy, Debug, Serialize, Deserialize)]
 pub struct Player {
     pub id: PlayerId,
@@ -96,15 +120,29 @@ impl Player {
     }
 }
 
-#[derive(Default, Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(Default, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub struct RawPlayerInput {
     pub x_move: i8,
     pub y_move: i8,
     pub shoot: bool,
+    /// Aim direction as an angle in radians.
     pub x_aim: f32,
+    /// How many frames behind the input frame the player's view of other players was, used
+    /// for lag compensation.
+    pub view_delay: u8,
 }
 
 impl RawPlayerInput {
+    /// Folds a later input for the same frame into this one. Latched buttons are OR-ed so
+    /// that a tap between ticks is not lost, axes take the latest value.
+    pub fn aggregate(&mut self, later: &RawPlayerInput) {
+        self.x_move = later.x_move;
+        self.y_move = later.y_move;
+        self.x_aim = later.x_aim;
+        self.view_delay = later.view_delay;
+        self.shoot |= later.shoot;
+    }
+
     pub fn at_frame(&self, frame: u64) -> FramedPlayerInput {
         FramedPlayerInput { raw: *self, frame }
     }
@@ -116,6 +154,29 @@ pub struct FramedPlayerInput {
     pub frame: u64,
 }
 
+/// How the server applied an input stamped by the client. Only sent when an input was not
+/// accepted as is, acceptance is covered by `UMFromServer::InputsReceived`.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum InputAck {
+    /// Applied on the frame the client stamped it with.
+    Accepted { frame: u64 },
+    /// Arrived too late to roll back to and was applied on a later frame instead.
+    Shifted { from: u64, to: u64 },
+    /// Stamped too far in the future and dropped.
+    Rejected { frame: u64 },
+}
+
+impl InputAck {
+    /// Frame the input was applied on by the server, if at all.
+    pub fn applied_frame(&self) -> Option<u64> {
+        match self {
+            InputAck::Accepted { frame } => Some(*frame),
+            InputAck::Shifted { to, .. } => Some(*to),
+            InputAck::Rejected { .. } => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct IdPlayerInput {
     pub player_id: PlayerId,
@@ -125,25 +186,257 @@ pub struct IdPlayerInput {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 /// Reliable Ordered Message from Server
 pub enum ROMFromServer {
-    PlayerConnected {
-        player_data: PlayerData,
-        server_object: ServerObject,
+    ObjectEntered(ObjectEntered),
+    ObjectLeft(ObjectLeft),
+    ComponentsChanged(ComponentsChanged),
+    LoginAccepted(LoginAccepted),
+    LoginRejected(LoginRejected),
+    GameJoined(GameJoined),
+    LobbyState(LobbyState),
+    LobbyRejected(LobbyRejected),
+    ShutdownNotice(ShutdownNotice),
+    Chat(ChatMessage),
+}
+impl_net_message!(ROMFromServer, ServerChannel::Events, 512 * 1024);
+
+/// The server is shutting down and disconnects every client once the countdown ends.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ShutdownNotice {
+    pub seconds_left: u32,
+}
+
+/// Longest chat message in characters.
+pub const MAX_CHAT_LENGTH: usize = 200;
+
+/// Who a chat message is for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChatScope {
+    Global,
+    /// Players near the sender.
+    Proximity,
+    Whisper(PlayerId),
+}
+
+/// A chat message relayed by the server.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatMessage {
+    /// `None` for notices from the server, e.g. why a message was not sent.
+    pub sender: Option<PlayerId>,
+    pub scope: ChatScope,
+    pub text: String,
+}
+
+/// A replicated server object entered the client's area of interest.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ObjectEntered {
+    pub server_object: ServerObject,
+    pub components: Vec<ReplicatedComponent>,
+}
+
+/// A server object left the client's area of interest or was despawned.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ObjectLeft {
+    pub server_object: ServerObject,
+    /// The object was despawned, the client answers with a `DespawnAck` so its id can be reused.
+    pub despawned: bool,
+}
+
+/// Replicated components of a server object in the client's area of interest changed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ComponentsChanged {
+    pub server_object: ServerObject,
+    pub components: Vec<ReplicatedComponent>,
+}
+
+/// The player entered the lobby.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoginAccepted {
+    pub player_id: PlayerId,
+}
+
+/// The player entered the game, once its room started a match, its session was resumed or it
+/// moved to another instance.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GameJoined {
+    pub player_id: PlayerId,
+    pub instance: InstanceId,
+    pub server_object: ServerObject,
+    /// Frame the player's entity is spawned on.
+    pub frame: u64,
+    /// Duration of a frame on the server, which the client adopts.
+    pub frame_duration: Duration,
+    /// Resumes the session with `PlayerLogin::resume` if the connection drops.
+    pub session: SessionToken,
+    /// Initial state of the world, including the player's entity.
+    pub game_sync: GameSync,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoginRejected {
+    pub reason: LoginRejectReason,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum LoginRejectReason {
+    ProtocolMismatch {
+        server_version: u64,
+        client_version: u64,
     },
-    PlayerDisconnected(PlayerId),
-    GameSync(GameSync),
+    AlreadyLoggedIn,
+    /// The connection was not made with a valid connect token.
+    NotAuthenticated,
+    /// Another client is already logged in as this player.
+    PlayerIdInUse(PlayerId),
+    ServerFull {
+        max_players: usize,
+    },
+    /// The session to resume ended, or was never started.
+    SessionExpired,
+}
+
+impl std::fmt::Display for LoginRejectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoginRejectReason::ProtocolMismatch {
+                server_version,
+                client_version,
+            } => write!(
+                f,
+                "Server runs protocol version {:016x}, client runs {:016x}",
+                server_version, client_version
+            ),
+            LoginRejectReason::AlreadyLoggedIn => write!(f, "Already logged in"),
+            LoginRejectReason::NotAuthenticated => write!(f, "Not authenticated"),
+            LoginRejectReason::PlayerIdInUse(player_id) => {
+                write!(f, "Player {} is already logged in", player_id)
+            }
+            LoginRejectReason::ServerFull { max_players } => {
+                write!(f, "Server is full ({} players)", max_players)
+            }
+            LoginRejectReason::SessionExpired => write!(f, "Session expired"),
+        }
+    }
+}
+
+/// Identifies a game instance of the server. Each instance simulates its own world, with its
+/// own frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct InstanceId(pub u32);
+
+impl std::fmt::Display for InstanceId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Moves the player to another instance, which sends a fresh `GameJoined`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransferRequest {
+    pub instance: InstanceId,
+}
+
+/// The client removed a despawned server object, sent reliably after the `ObjectLeft`.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct DespawnAck(pub ServerObject);
+
+/// Most players in a room.
+pub const ROOM_MAX_PLAYERS: usize = 8;
+/// Longest room name in characters.
+pub const MAX_ROOM_NAME_LENGTH: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct RoomId(pub u32);
+
+impl std::fmt::Display for RoomId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// A room waiting in the lobby for its players to be ready.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomInfo {
+    pub id: RoomId,
+    pub name: String,
+    /// Players in the room and whether they are ready.
+    pub players: Vec<(PlayerId, bool)>,
+}
+
+/// Rooms in the lobby, sent to every player in the lobby when they change.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LobbyState {
+    pub rooms: Vec<RoomInfo>,
+    /// Room the receiving player is in.
+    pub room: Option<RoomId>,
+    /// Players a room needs, all ready, to start a match.
+    pub min_match_players: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum LobbyRequest {
+    CreateRoom { name: String },
+    JoinRoom(RoomId),
+    LeaveRoom,
+    SetReady(bool),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LobbyRejected {
+    pub reason: LobbyRejectReason,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum LobbyRejectReason {
+    /// The player is not in the lobby, e.g. it is in a match.
+    NotInLobby,
+    InvalidRoomName,
+    NoSuchRoom(RoomId),
+    RoomFull(RoomId),
+    /// The player must be in a room to be ready.
+    NotInRoom,
+}
+
+impl std::fmt::Display for LobbyRejectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LobbyRejectReason::NotInLobby => write!(f, "Not in the lobby"),
+            LobbyRejectReason::InvalidRoomName => write!(
+                f,
+                "Room names must have 1 to {} characters",
+                MAX_ROOM_NAME_LENGTH
+            ),
+            LobbyRejectReason::NoSuchRoom(room_id) => write!(f, "Room {} does not exist", room_id),
+            LobbyRejectReason::RoomFull(room_id) => {
+                write!(f, "Room {} is full ({} players)", room_id, ROOM_MAX_PLAYERS)
+            }
+            LobbyRejectReason::NotInRoom => write!(f, "Not in a room"),
+        }
+    }
 }
-impl_bytes!(ROMFromServer);
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 /// Reliable Ordered Message from Client
 pub enum ROMFromClient {
     PlayerLogin(PlayerLogin),
+    Chat(ChatRequest),
+    Lobby(LobbyRequest),
+    Transfer(TransferRequest),
+    DespawnAck(DespawnAck),
 }
-impl_bytes!(ROMFromClient);
+impl_net_message!(ROMFromClient, ClientChannel::Events, 1024);
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PlayerLogin {
-    pub id: PlayerId,
+    pub protocol_version: u64,
+    /// Session of a previous connection to resume, keeping the player's entity.
+    pub resume: Option<SessionToken>,
+}
+
+/// A chat message to send, at most `MAX_CHAT_LENGTH` characters.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatRequest {
+    pub scope: ChatScope,
+    pub text: String,
 }
 
 #[derive(Clone, Default, Debug)]
@@ -152,10 +445,29 @@ pub struct InputBuffer(pub HashMap<PlayerId, RawPlayerInput>);
 #[derive(Debug, Clone, Serialize, Deserialize)]
 /// Unreliable Message from Server
 pub enum UMFromServer {
-    IdPlayerInput(IdPlayerInput),
+    IdPlayerInputs(IdPlayerInputs),
+    InputAck(InputAck),
+    InputsReceived(InputsReceived),
+    /// The client's link as measured by the server, sent every tick.
+    LinkStats(LinkStats),
+}
+impl_net_message!(UMFromServer, ServerChannel::Input, 16 * 1024);
+
+/// Most recent inputs of a single player, oldest first.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IdPlayerInputs(pub Vec<IdPlayerInput>);
+
+/// Highest frame up to which every input of the receiving client has arrived.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct InputsReceived(pub u64);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Unreliable game state message from Server
+pub enum SnapshotFromServer {
     GameSync(GameSync),
+    GameSyncDelta(GameSyncDelta),
 }
-impl_bytes!(UMFromServer);
+impl_net_message!(SnapshotFromServer, ServerChannel::Snapshot, 512 * 1024);
 
 /// `GameSync` contains a (possibly incomplete) update of component values for server objects.
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -163,6 +475,10 @@ pub struct GameSync {
     pub frame: u64,
     /// Unix time this sync was generated in seconds.
     pub unix_time: f64,
+    #[serde(
+        serialize_with = "quantize::serialize_transforms",
+        deserialize_with = "quantize::deserialize_transforms"
+    )]
     pub transforms: HashMap<ServerObject, Transform>,
     pub players: HashMap<ServerObject, Player>,
 }
@@ -174,6 +490,26 @@ macro_rules! cast {
 }
 
 impl GameSync {
+    /// Returns a copy of this sync with only the server objects matching `include`.
+    pub fn filtered(&self, include: impl Fn(&ServerObject) -> bool) -> GameSync {
+        GameSync {
+            frame: self.frame,
+            unix_time: self.unix_time,
+            transforms: self
+                .transforms
+                .iter()
+                .filter(|(server_obj, _)| include(server_obj))
+                .map(|(server_obj, transform)| (*server_obj, *transform))
+                .collect(),
+            players: self
+                .players
+                .iter()
+                .filter(|(server_obj, _)| include(server_obj))
+                .map(|(server_obj, player)| (*server_obj, *player))
+                .collect(),
+        }
+    }
+
     pub fn get<T: Component>(&self) -> Option<&HashMap<ServerObject, T>> {
         if TypeId::of::<T>() == TypeId::of::<Transform>() {
             Some(cast!(&self.transforms, HashMap<ServerObject, T>))
@@ -188,31 +524,78 @@ impl GameSync {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 /// Unreliable Message from Client
 pub enum UMFromClient {
-    PlayerInput(RawPlayerInput),
+    PlayerInput(PlayerInputs),
+    GameSyncAck(GameSyncAck),
 }
-impl_bytes!(UMFromClient);
+impl_net_message!(UMFromClient, ClientChannel::Input, 8 * 1024);
 
+/// Unacknowledged local inputs, oldest first.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlayerInputs(pub Vec<FramedPlayerInput>);
+
+/// Frame of the most recent game sync the client applied, used as the delta baseline.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct GameSyncAck(pub u64);
+
+/// Id of an object the server replicates. Ids are allocated by the server, an index is only
+/// reused with a new generation.
 #[derive(Default, Component, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Debug, Hash)]
 pub struct ServerObject(u64);
 
 impl ServerObject {
-    pub fn rand() -> Self {
-        Self(rand::random())
+    pub fn new(index: u32, generation: u32) -> Self {
+        Self((generation as u64) << 32 | index as u64)
+    }
+
+    pub fn index(&self) -> u32 {
+        self.0 as u32
+    }
+
+    pub fn generation(&self) -> u32 {
+        (self.0 >> 32) as u32
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServerEntityMapError {
+    /// The `ServerObject` is already mapped to `entity`.
+    AlreadyMapped {
+        server_object: ServerObject,
+        entity: Entity,
+    },
+}
+
+impl std::fmt::Display for ServerEntityMapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::AlreadyMapped {
+                server_object,
+                entity,
+            } => write!(f, "{:?} is already mapped to {:?}", server_object, entity),
+        }
     }
 }
 
+impl std::error::Error for ServerEntityMapError {}
+
 #[derive(Default, Resource)]
 pub struct ServerEntityMap(HashMap<ServerObject, Entity>);
 
 impl ServerEntityMap {
-    /// Returns `Err` if the `ServerObject` is already in the map.
-    pub fn insert(&mut self, server_object: ServerObject, entity: Entity) -> Result<(), ()> {
-        // @TODO: better errors
-        if self.0.contains_key(&server_object) {
-            Err(())
-        } else {
-            self.0.insert(server_object, entity);
-            Ok(())
+    pub fn insert(
+        &mut self,
+        server_object: ServerObject,
+        entity: Entity,
+    ) -> Result<(), ServerEntityMapError> {
+        match self.0.get(&server_object) {
+            Some(entity) => Err(ServerEntityMapError::AlreadyMapped {
+                server_object,
+                entity: *entity,
+            }),
+            None => {
+                self.0.insert(server_object, entity);
+                Ok(())
+            }
         }
     }
 
@@ -232,7 +615,44 @@ pub fn get_unix_time() -> f64 {
         .as_secs_f64()
 }
 
-pub fn frames_since_unix_time(unix_time: f64) -> u64 {
+pub fn frames_since_unix_time(unix_time: f64, frame_duration: Duration) -> u64 {
     let current_time = get_unix_time();
-    ((current_time - unix_time) / FRAME_DURATION_SECONDS) as u64
+    ((current_time - unix_time) / frame_duration.as_secs_f64()) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aggregate_latches_buttons_and_keeps_the_latest_axes() {
+        let mut input = RawPlayerInput {
+            x_move: 1,
+            y_move: -1,
+            shoot: true,
+            x_aim: 0.5,
+            view_delay: 2,
+        };
+        let later = RawPlayerInput {
+            x_move: 0,
+            y_move: 1,
+            shoot: false,
+            x_aim: -1.0,
+            view_delay: 3,
+        };
+        input.aggregate(&later);
+        assert_eq!(
+            input,
+            RawPlayerInput {
+                shoot: true,
+                ..later
+            }
+        );
+
+        // A released key clears its axis.
+        input.aggregate(&RawPlayerInput::default());
+        assert_eq!(input.x_move, 0);
+        assert_eq!(input.y_move, 0);
+        assert!(input.shoot);
+    }
 }
@@ -0,0 +1,241 @@
+use std::fmt;
+
+use bevy::prelude::*;
+use bevy_renet::renet::{Bytes, ClientId};
+use bincode::Options;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    channel::{ClientChannel, ServerChannel},
+    delta::GameSyncDelta,
+    stats::LinkStats,
+    ChatMessage, ChatRequest, ComponentsChanged, DespawnAck, GameJoined, GameSync, GameSyncAck,
+    IdPlayerInputs, InputAck, InputsReceived, LobbyRejected, LobbyRequest, LobbyState,
+    LoginAccepted, LoginRejected, ObjectEntered, ObjectLeft, PlayerInputs, PlayerLogin,
+    ROMFromClient, ROMFromServer, ShutdownNotice, SnapshotFromServer, TransferRequest,
+    UMFromClient, UMFromServer,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    ServerToClient,
+    ClientToServer,
+}
+
+#[derive(Debug)]
+pub enum MessageError {
+    TooLarge { size: usize, max_size: usize },
+    Bincode(bincode::Error),
+}
+
+impl fmt::Display for MessageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooLarge { size, max_size } => {
+                write!(
+                    f,
+                    "message of {} bytes is over the limit of {}",
+                    size, max_size
+                )
+            }
+            Self::Bincode(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for MessageError {}
+
+impl From<bincode::Error> for MessageError {
+    fn from(err: bincode::Error) -> Self {
+        Self::Bincode(err)
+    }
+}
+
+/// A message sent over renet, always on the same channel and in the same direction.
+pub trait NetMessage: Serialize + DeserializeOwned + Send + Sync + 'static {
+    /// `ServerChannel` for messages the server sends, `ClientChannel` for the client.
+    type Channel: Into<u8> + Copy;
+
+    const CHANNEL: Self::Channel;
+    const DIRECTION: Direction;
+    /// Largest encoded size in bytes. Larger messages are neither sent nor decoded.
+    const MAX_SIZE: usize;
+
+    fn encode(&self) -> Result<Bytes, MessageError> {
+        Ok(bincode_options(Self::MAX_SIZE).serialize(self)?.into())
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, MessageError> {
+        if bytes.len() > Self::MAX_SIZE {
+            return Err(MessageError::TooLarge {
+                size: bytes.len(),
+                max_size: Self::MAX_SIZE,
+            });
+        }
+        Ok(bincode_options(Self::MAX_SIZE).deserialize(bytes)?)
+    }
+}
+
+/// Same encoding as `bincode::serialize`, with a size limit.
+fn bincode_options(max_size: usize) -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(max_size as u64)
+}
+
+/// A message variant received from a client.
+#[derive(Debug)]
+pub struct FromClient<T> {
+    pub client_id: ClientId,
+    pub message: T,
+}
+
+impl<T: Send + Sync + 'static> Event for FromClient<T> {}
+
+/// A message variant received from the server.
+#[derive(Debug)]
+pub struct FromServer<T> {
+    pub message: T,
+}
+
+impl<T: Send + Sync + 'static> Event for FromServer<T> {}
+
+/// A message the server receives, split into a `FromClient` event per variant.
+pub trait ClientMessageEvents: NetMessage<Channel = ClientChannel> {
+    fn add_events(app: &mut App);
+
+    fn send_events(self, client_id: ClientId, world: &mut World);
+}
+
+/// A message the client receives, split into a `FromServer` event per variant.
+pub trait ServerMessageEvents: NetMessage<Channel = ServerChannel> {
+    fn add_events(app: &mut App);
+
+    fn send_events(self, world: &mut World);
+}
+
+fn send_from_client<T: Send + Sync + 'static>(world: &mut World, client_id: ClientId, message: T) {
+    world.send_event(FromClient { client_id, message });
+}
+
+fn send_from_server<T: Send + Sync + 'static>(world: &mut World, message: T) {
+    world.send_event(FromServer { message });
+}
+
+impl ClientMessageEvents for UMFromClient {
+    fn add_events(app: &mut App) {
+        app.add_event::<FromClient<PlayerInputs>>()
+            .add_event::<FromClient<GameSyncAck>>();
+    }
+
+    fn send_events(self, client_id: ClientId, world: &mut World) {
+        match self {
+            Self::PlayerInput(message) => send_from_client(world, client_id, message),
+            Self::GameSyncAck(message) => send_from_client(world, client_id, message),
+        }
+    }
+}
+
+impl ClientMessageEvents for ROMFromClient {
+    fn add_events(app: &mut App) {
+        app.add_event::<FromClient<PlayerLogin>>()
+            .add_event::<FromClient<ChatRequest>>()
+            .add_event::<FromClient<LobbyRequest>>()
+            .add_event::<FromClient<TransferRequest>>()
+            .add_event::<FromClient<DespawnAck>>();
+    }
+
+    fn send_events(self, client_id: ClientId, world: &mut World) {
+        match self {
+            Self::PlayerLogin(message) => send_from_client(world, client_id, message),
+            Self::Chat(message) => send_from_client(world, client_id, message),
+            Self::Lobby(message) => send_from_client(world, client_id, message),
+            Self::Transfer(message) => send_from_client(world, client_id, message),
+            Self::DespawnAck(message) => send_from_client(world, client_id, message),
+        }
+    }
+}
+
+impl ServerMessageEvents for UMFromServer {
+    fn add_events(app: &mut App) {
+        app.add_event::<FromServer<IdPlayerInputs>>()
+            .add_event::<FromServer<InputAck>>()
+            .add_event::<FromServer<InputsReceived>>()
+            .add_event::<FromServer<LinkStats>>();
+    }
+
+    fn send_events(self, world: &mut World) {
+        match self {
+            Self::IdPlayerInputs(message) => send_from_server(world, message),
+            Self::InputAck(message) => send_from_server(world, message),
+            Self::InputsReceived(message) => send_from_server(world, message),
+            Self::LinkStats(message) => send_from_server(world, message),
+        }
+    }
+}
+
+impl ServerMessageEvents for SnapshotFromServer {
+    fn add_events(app: &mut App) {
+        app.add_event::<FromServer<GameSync>>()
+            .add_event::<FromServer<GameSyncDelta>>();
+    }
+
+    fn send_events(self, world: &mut World) {
+        match self {
+            Self::GameSync(message) => send_from_server(world, message),
+            Self::GameSyncDelta(message) => send_from_server(world, message),
+        }
+    }
+}
+
+impl ServerMessageEvents for ROMFromServer {
+    fn add_events(app: &mut App) {
+        app.add_event::<FromServer<ObjectEntered>>()
+            .add_event::<FromServer<ObjectLeft>>()
+            .add_event::<FromServer<ComponentsChanged>>()
+            .add_event::<FromServer<LoginAccepted>>()
+            .add_event::<FromServer<LoginRejected>>()
+            .add_event::<FromServer<GameJoined>>()
+            .add_event::<FromServer<LobbyState>>()
+            .add_event::<FromServer<LobbyRejected>>()
+            .add_event::<FromServer<ShutdownNotice>>()
+            .add_event::<FromServer<ChatMessage>>();
+    }
+
+    fn send_events(self, world: &mut World) {
+        match self {
+            Self::ObjectEntered(message) => send_from_server(world, message),
+            Self::ObjectLeft(message) => send_from_server(world, message),
+            Self::ComponentsChanged(message) => send_from_server(world, message),
+            Self::LoginAccepted(message) => send_from_server(world, message),
+            Self::LoginRejected(message) => send_from_server(world, message),
+            Self::GameJoined(message) => send_from_server(world, message),
+            Self::LobbyState(message) => send_from_server(world, message),
+            Self::LobbyRejected(message) => send_from_server(world, message),
+            Self::ShutdownNotice(message) => send_from_server(world, message),
+            Self::Chat(message) => send_from_server(world, message),
+        }
+    }
+}
+
+/// Registers the events of every message the server receives.
+pub struct ServerMessagesPlugin;
+
+impl Plugin for ServerMessagesPlugin {
+    fn build(&self, app: &mut App) {
+        UMFromClient::add_events(app);
+        ROMFromClient::add_events(app);
+    }
+}
+
+/// Registers the events of every message the client receives.
+pub struct ClientMessagesPlugin;
+
+impl Plugin for ClientMessagesPlugin {
+    fn build(&self, app: &mut App) {
+        UMFromServer::add_events(app);
+        SnapshotFromServer::add_events(app);
+        ROMFromServer::add_events(app);
+    }
+}
@@ -0,0 +1,225 @@
+/// Version of the network protocol, checked on connect and bound into connect tokens. Bump it
+/// whenever the encoding of a message changes, the `wire_layout_is_pinned` test fails until
+/// it is.
+pub const PROTOCOL_VERSION: u64 = 2;
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bevy::prelude::*;
+
+    use super::*;
+    use crate::{
+        delta::{GameSyncDelta, PlayerDelta, TransformDelta},
+        message::NetMessage,
+        replication::ReplicatedComponent,
+        stats::LinkStats,
+        ChatMessage, ChatRequest, ChatScope, ComponentsChanged, DespawnAck, FramedPlayerInput,
+        GameJoined, GameSync, GameSyncAck, IdPlayerInput, IdPlayerInputs, InputAck, InputsReceived,
+        InstanceId, LobbyRejectReason, LobbyRejected, LobbyRequest, LobbyState, LoginAccepted,
+        LoginRejectReason, LoginRejected, ObjectEntered, ObjectLeft, Player, PlayerId,
+        PlayerInputs, PlayerLogin, ROMFromClient, ROMFromServer, RawPlayerInput, RoomId, RoomInfo,
+        ServerObject, SessionToken, ShutdownNotice, SnapshotFromServer, TransferRequest,
+        UMFromClient, UMFromServer,
+    };
+
+    /// `PROTOCOL_VERSION` and the hash of the encoded sample messages it was set for.
+    const PINNED_LAYOUT: (u64, u64) = (2, 0xed196d13ab29d8a9);
+
+    const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const FNV_PRIME: u64 = 0x100000001b3;
+
+    fn fnv1a(bytes: &[u8], mut hash: u64) -> u64 {
+        for byte in bytes {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(FNV_PRIME);
+        }
+        hash
+    }
+
+    fn server_object() -> ServerObject {
+        ServerObject::new(3, 1)
+    }
+
+    fn input() -> FramedPlayerInput {
+        RawPlayerInput {
+            x_move: 1,
+            y_move: -1,
+            shoot: true,
+            x_aim: 0.5,
+            view_delay: 2,
+        }
+        .at_frame(42)
+    }
+
+    fn components() -> Vec<ReplicatedComponent> {
+        vec![ReplicatedComponent {
+            id: 1,
+            data: vec![1, 2, 3],
+        }]
+    }
+
+    /// A sync with a single object, as maps of several are encoded in any order.
+    fn game_sync() -> GameSync {
+        GameSync {
+            frame: 7,
+            unix_time: 1.5,
+            transforms: [(
+                server_object(),
+                Transform::from_xyz(1.0, -2.0, 0.0).with_rotation(Quat::from_rotation_z(1.0)),
+            )]
+            .into_iter()
+            .collect(),
+            players: [(server_object(), Player::new(PlayerId(9)))]
+                .into_iter()
+                .collect(),
+        }
+    }
+
+    fn game_sync_delta() -> GameSyncDelta {
+        GameSyncDelta {
+            frame: 8,
+            baseline_frame: 7,
+            unix_time: 1.6,
+            transforms: [(
+                server_object(),
+                TransformDelta {
+                    position: Some([1, 2]),
+                    angle: None,
+                    scale: Some(None),
+                },
+            )]
+            .into_iter()
+            .collect(),
+            players: [(
+                server_object(),
+                PlayerDelta {
+                    id: None,
+                    speed: Some(50.0),
+                },
+            )]
+            .into_iter()
+            .collect(),
+            removed: vec![ServerObject::new(4, 0)],
+        }
+    }
+
+    fn encoded<M: NetMessage>(messages: Vec<M>) -> Vec<Vec<u8>> {
+        messages
+            .iter()
+            .map(|message| message.encode().unwrap().to_vec())
+            .collect()
+    }
+
+    /// One sample of every message variant, encoded.
+    fn sample_messages() -> Vec<Vec<u8>> {
+        let mut messages = encoded(vec![
+            ROMFromServer::ObjectEntered(ObjectEntered {
+                server_object: server_object(),
+                components: components(),
+            }),
+            ROMFromServer::ObjectLeft(ObjectLeft {
+                server_object: server_object(),
+                despawned: true,
+            }),
+            ROMFromServer::ComponentsChanged(ComponentsChanged {
+                server_object: server_object(),
+                components: components(),
+            }),
+            ROMFromServer::LoginAccepted(LoginAccepted {
+                player_id: PlayerId(9),
+            }),
+            ROMFromServer::LoginRejected(LoginRejected {
+                reason: LoginRejectReason::ProtocolMismatch {
+                    server_version: 1,
+                    client_version: 2,
+                },
+            }),
+            ROMFromServer::LoginRejected(LoginRejected {
+                reason: LoginRejectReason::ServerFull { max_players: 8 },
+            }),
+            ROMFromServer::GameJoined(GameJoined {
+                player_id: PlayerId(9),
+                instance: InstanceId(2),
+                server_object: server_object(),
+                frame: 8,
+                frame_duration: Duration::from_millis(50),
+                session: SessionToken(123),
+                game_sync: game_sync(),
+            }),
+            ROMFromServer::LobbyState(LobbyState {
+                rooms: vec![RoomInfo {
+                    id: RoomId(1),
+                    name: "Room".to_string(),
+                    players: vec![(PlayerId(9), true)],
+                }],
+                room: Some(RoomId(1)),
+                min_match_players: 2,
+            }),
+            ROMFromServer::LobbyRejected(LobbyRejected {
+                reason: LobbyRejectReason::RoomFull(RoomId(1)),
+            }),
+            ROMFromServer::ShutdownNotice(ShutdownNotice { seconds_left: 10 }),
+            ROMFromServer::Chat(ChatMessage {
+                sender: Some(PlayerId(9)),
+                scope: ChatScope::Whisper(PlayerId(10)),
+                text: "Hi".to_string(),
+            }),
+        ]);
+        messages.extend(encoded(vec![
+            ROMFromClient::PlayerLogin(PlayerLogin {
+                protocol_version: PROTOCOL_VERSION,
+                resume: Some(SessionToken(123)),
+            }),
+            ROMFromClient::Chat(ChatRequest {
+                scope: ChatScope::Proximity,
+                text: "Hi".to_string(),
+            }),
+            ROMFromClient::Lobby(LobbyRequest::CreateRoom {
+                name: "Room".to_string(),
+            }),
+            ROMFromClient::Lobby(LobbyRequest::SetReady(true)),
+            ROMFromClient::Transfer(TransferRequest {
+                instance: InstanceId(2),
+            }),
+            ROMFromClient::DespawnAck(DespawnAck(server_object())),
+        ]));
+        messages.extend(encoded(vec![
+            UMFromServer::IdPlayerInputs(IdPlayerInputs(vec![IdPlayerInput {
+                player_id: PlayerId(9),
+                input: input(),
+            }])),
+            UMFromServer::InputAck(InputAck::Shifted { from: 40, to: 42 }),
+            UMFromServer::InputsReceived(InputsReceived(42)),
+            UMFromServer::LinkStats(LinkStats {
+                rtt: 0.1,
+                jitter: 0.01,
+                packet_loss: 0.05,
+                bytes_sent_per_second: 1000.0,
+                bytes_received_per_second: 2000.0,
+            }),
+        ]));
+        messages.extend(encoded(vec![
+            SnapshotFromServer::GameSync(game_sync()),
+            SnapshotFromServer::GameSyncDelta(game_sync_delta()),
+        ]));
+        messages.extend(encoded(vec![
+            UMFromClient::PlayerInput(PlayerInputs(vec![input()])),
+            UMFromClient::GameSyncAck(GameSyncAck(7)),
+        ]));
+        messages
+    }
+
+    #[test]
+    fn wire_layout_is_pinned() {
+        let hash = sample_messages()
+            .iter()
+            .fold(FNV_OFFSET_BASIS, |hash, bytes| fnv1a(bytes, hash));
+        assert_eq!(
+            (PROTOCOL_VERSION, hash),
+            PINNED_LAYOUT,
+            "the encoding of messages changed, bump PROTOCOL_VERSION and pin the new hash"
+        );
+    }
+}
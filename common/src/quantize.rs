@@ -0,0 +1,243 @@
+use std::f32::consts::TAU;
+
+use bevy::{prelude::*, utils::HashMap};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::ServerObject;
+
+/// How finely transform components are quantized for the network.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TransformPrecision {
+    /// Size of a position step in pixels.
+    pub position_step: f32,
+    /// Number of bits a full turn is split into, at most 16.
+    pub angle_bits: u32,
+    /// Size of a scale step. Scales are sent from `i16::MIN` to `i16::MAX` steps, so within
+    /// -128 to about 128 at a step of 1/256.
+    pub scale_step: f32,
+}
+
+/// Precision transforms are sent with. Steps are powers of two so that quantized values are
+/// exactly representable as `f32`.
+pub const TRANSFORM_PRECISION: TransformPrecision = TransformPrecision {
+    position_step: 1.0 / 64.0,
+    angle_bits: 16,
+    scale_step: 1.0 / 256.0,
+};
+
+/// Network representation of a 2D `Transform`. The z translation and scale are dropped on
+/// purpose, as is any rotation not around the z axis: the game is played on a plane and z would
+/// only layer sprites, which is not simulated state. Decoded transforms have a z of 0 and a z
+/// scale of 1.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NetTransform {
+    pub position: [i32; 2],
+    pub angle: u16,
+    /// `None` if the scale is one. Scales beyond the range of `i16` steps are clamped.
+    pub scale: Option<[i16; 2]>,
+}
+
+impl NetTransform {
+    pub fn quantize(transform: &Transform, precision: &TransformPrecision) -> Self {
+        let position = transform.translation.truncate() / precision.position_step;
+
+        let steps = (1u32 << precision.angle_bits) as f32;
+        let rotation = transform.rotation;
+        let angle = (2.0 * rotation.z.atan2(rotation.w)).rem_euclid(TAU);
+        let angle = ((angle / TAU * steps).round() as u32 % (1 << precision.angle_bits)) as u16;
+
+        let scale = (transform.scale.truncate() / precision.scale_step).round();
+        let (min, max) = (i16::MIN as f32, i16::MAX as f32);
+        debug_assert!(
+            scale.cmpge(Vec2::splat(min)).all() && scale.cmple(Vec2::splat(max)).all(),
+            "scale {} is out of the range sent over the network",
+            transform.scale
+        );
+        let scale = scale.clamp(Vec2::splat(min), Vec2::splat(max));
+        let one = (Vec2::ONE / precision.scale_step).round();
+
+        Self {
+            position: [position.x.round() as i32, position.y.round() as i32],
+            angle,
+            scale: (scale != one).then_some([scale.x as i16, scale.y as i16]),
+        }
+    }
+
+    /// Quantizing the returned transform again gives back `self`.
+    pub fn to_transform(&self, precision: &TransformPrecision) -> Transform {
+        let steps = (1u32 << precision.angle_bits) as f32;
+        let scale = match self.scale {
+            Some([x, y]) => Vec3::new(x as f32, y as f32, 0.0) * precision.scale_step,
+            None => Vec3::ONE,
+        };
+
+        Transform {
+            translation: Vec3::new(self.position[0] as f32, self.position[1] as f32, 0.0)
+                * precision.position_step,
+            rotation: Quat::from_rotation_z(self.angle as f32 / steps * TAU),
+            scale: scale.truncate().extend(1.0),
+        }
+    }
+}
+
+/// Serializes a transform as a `NetTransform` with `TRANSFORM_PRECISION`.
+pub fn serialize_transform<S: Serializer>(
+    transform: &Transform,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    NetTransform::quantize(transform, &TRANSFORM_PRECISION).serialize(serializer)
+}
+
+pub fn deserialize_transform<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Transform, D::Error> {
+    Ok(NetTransform::deserialize(deserializer)?.to_transform(&TRANSFORM_PRECISION))
+}
+
+/// Serializes transforms as `NetTransform`s with `TRANSFORM_PRECISION`.
+pub fn serialize_transforms<S: Serializer>(
+    transforms: &HashMap<ServerObject, Transform>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(transforms.iter().map(|(server_obj, transform)| {
+        (
+            server_obj,
+            NetTransform::quantize(transform, &TRANSFORM_PRECISION),
+        )
+    }))
+}
+
+pub fn deserialize_transforms<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<HashMap<ServerObject, Transform>, D::Error> {
+    Ok(
+        HashMap::<ServerObject, NetTransform>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(server_obj, net)| (server_obj, net.to_transform(&TRANSFORM_PRECISION)))
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{delta::GameSyncDelta, GameSync, Player, PlayerId};
+
+    fn transforms() -> Vec<Transform> {
+        vec![
+            Transform::default(),
+            Transform::from_xyz(12.345, -678.9, 0.0),
+            Transform::from_xyz(-0.001, 100_000.5, 0.0).with_rotation(Quat::from_rotation_z(1.0)),
+            Transform::from_xyz(3.0, 4.0, 0.0)
+                .with_rotation(Quat::from_rotation_z(-0.00001))
+                .with_scale(Vec3::new(2.5, 0.75, 1.0)),
+            Transform::default().with_rotation(Quat::from_rotation_z(TAU - 0.00001)),
+            Transform::default().with_scale(Vec3::new(127.99, -128.0, 1.0)),
+        ]
+    }
+
+    fn game_sync() -> GameSync {
+        let objects = transforms()
+            .into_iter()
+            .enumerate()
+            .map(|(i, transform)| (ServerObject::new(i as u32, 0), i, transform))
+            .collect::<Vec<_>>();
+        GameSync {
+            frame: 1,
+            unix_time: 0.0,
+            transforms: objects
+                .iter()
+                .map(|(server_obj, _, transform)| (*server_obj, *transform))
+                .collect(),
+            players: objects
+                .iter()
+                .map(|(server_obj, i, _)| {
+                    let player = Player {
+                        id: PlayerId(*i as u64),
+                        speed: 100.0,
+                    };
+                    (*server_obj, player)
+                })
+                .collect(),
+        }
+    }
+
+    fn encode_decode(game_sync: &GameSync) -> GameSync {
+        bincode::deserialize(&bincode::serialize(game_sync).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn quantization_is_within_precision() {
+        let precision = TRANSFORM_PRECISION;
+        let angle_step = TAU / (1u32 << precision.angle_bits) as f32;
+        for transform in transforms() {
+            let decoded = NetTransform::quantize(&transform, &precision).to_transform(&precision);
+            let position_error = (decoded.translation - transform.translation).abs();
+            assert!(position_error.max_element() <= precision.position_step / 2.0);
+            assert!(decoded.rotation.angle_between(transform.rotation) <= angle_step);
+            let scale_error = (decoded.scale - transform.scale).abs();
+            assert!(scale_error.max_element() <= precision.scale_step / 2.0);
+        }
+    }
+
+    #[test]
+    fn quantization_round_trips() {
+        for precision in [
+            TRANSFORM_PRECISION,
+            TransformPrecision {
+                position_step: 0.1,
+                angle_bits: 8,
+                scale_step: 0.01,
+            },
+        ] {
+            for transform in transforms() {
+                let net = NetTransform::quantize(&transform, &precision);
+                let decoded = net.to_transform(&precision);
+                assert_eq!(NetTransform::quantize(&decoded, &precision), net);
+            }
+        }
+    }
+
+    #[test]
+    fn z_is_not_sent() {
+        let transform = Transform::from_xyz(1.0, 2.0, 5.0)
+            .with_rotation(Quat::from_rotation_x(1.0))
+            .with_scale(Vec3::new(1.0, 1.0, 3.0));
+        let net = NetTransform::quantize(&transform, &TRANSFORM_PRECISION);
+        assert_eq!(
+            net,
+            NetTransform::quantize(&Transform::from_xyz(1.0, 2.0, 0.0), &TRANSFORM_PRECISION)
+        );
+        assert_eq!(
+            net.to_transform(&TRANSFORM_PRECISION),
+            Transform::from_xyz(1.0, 2.0, 0.0)
+        );
+    }
+
+    #[test]
+    fn decoded_game_sync_is_stable() {
+        let game_sync = encode_decode(&game_sync());
+        assert_eq!(encode_decode(&game_sync).transforms, game_sync.transforms);
+    }
+
+    #[test]
+    fn delta_matches_full_sync() {
+        let baseline = game_sync();
+        let mut game_sync = baseline.clone();
+        game_sync.frame = 2;
+        game_sync.transforms = baseline
+            .transforms
+            .iter()
+            .map(|(server_obj, transform)| {
+                (*server_obj, *transform * Transform::from_xyz(0.3, 0.0, 0.0))
+            })
+            .collect();
+
+        // The client only ever holds decoded syncs, the server diffs the exact ones.
+        let delta = GameSyncDelta::between(&baseline, &game_sync, |_| true);
+        let delta =
+            bincode::deserialize::<GameSyncDelta>(&bincode::serialize(&delta).unwrap()).unwrap();
+        let applied = delta.apply(&encode_decode(&baseline));
+        assert_eq!(applied.transforms, encode_decode(&game_sync).transforms);
+    }
+}
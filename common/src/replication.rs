@@ -0,0 +1,319 @@
+use bevy::{ecs::system::EntityCommands, prelude::*};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    channel::NetClient,
+    message::FromServer,
+    schedule::{ClientSchedule, ClientState, ServerSchedule},
+    ComponentsChanged, DespawnAck, ObjectEntered, ObjectLeft, Player, ROMFromClient,
+    ServerEntityMap, ServerObject,
+};
+
+/// Marks a `ServerObject` entity as replicated to the clients whose area of interest it is in.
+#[derive(Component, Default, Clone, Copy, Debug)]
+pub struct Replicated;
+
+/// A registered component, encoded with bincode.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplicatedComponent {
+    /// Index of the component in the `ReplicationRegistry`.
+    pub id: u16,
+    pub data: Vec<u8>,
+}
+
+struct ComponentFns {
+    name: &'static str,
+    serialize: fn(&EntityRef) -> Option<Vec<u8>>,
+    insert: fn(&mut EntityCommands, &[u8]) -> bincode::Result<()>,
+}
+
+/// Components replicated with an entity. Server and client must register the same components
+/// in the same order, so registration belongs in `ReplicationPlugin`.
+#[derive(Resource, Default)]
+pub struct ReplicationRegistry(Vec<ComponentFns>);
+
+impl ReplicationRegistry {
+    fn id<T: Component>(&self) -> u16 {
+        let name = std::any::type_name::<T>();
+        self.0
+            .iter()
+            .position(|fns| fns.name == name)
+            .unwrap_or_else(|| panic!("Component {} is not replicated", name)) as u16
+    }
+
+    /// Every registered component `entity` has.
+    pub fn serialize(&self, entity: &EntityRef) -> Vec<ReplicatedComponent> {
+        self.0
+            .iter()
+            .enumerate()
+            .filter_map(|(id, fns)| {
+                (fns.serialize)(entity).map(|data| ReplicatedComponent {
+                    id: id as u16,
+                    data,
+                })
+            })
+            .collect()
+    }
+
+    pub fn insert(&self, commands: &mut EntityCommands, components: &[ReplicatedComponent]) {
+        for component in components {
+            let Some(fns) = self.0.get(component.id as usize) else {
+                warn!("Unknown replicated component id {}", component.id);
+                continue;
+            };
+            if let Err(err) = (fns.insert)(commands, &component.data) {
+                warn!("Failed to decode replicated {}: {}", fns.name, err);
+            }
+        }
+    }
+}
+
+fn serialize_component<T: Component + Serialize>(entity: &EntityRef) -> Option<Vec<u8>> {
+    entity
+        .get::<T>()
+        .map(|component| bincode::serialize(component).unwrap())
+}
+
+fn insert_component<T: Component + DeserializeOwned>(
+    commands: &mut EntityCommands,
+    data: &[u8],
+) -> bincode::Result<()> {
+    commands.insert(bincode::deserialize::<T>(data)?);
+    Ok(())
+}
+
+/// Changes of replicated components since the last tick, collected on the server only.
+#[derive(Resource, Default)]
+pub struct ReplicationChanges(Vec<(ServerObject, ReplicatedComponent)>);
+
+impl ReplicationChanges {
+    pub fn drain(&mut self) -> impl Iterator<Item = (ServerObject, ReplicatedComponent)> + '_ {
+        self.0.drain(..)
+    }
+}
+
+fn collect_changes<T: Component + Serialize>(
+    registry: Res<ReplicationRegistry>,
+    mut changes: ResMut<ReplicationChanges>,
+    component_q: Query<(&ServerObject, Ref<T>), With<Replicated>>,
+) {
+    let id = registry.id::<T>();
+    for (server_obj, component) in component_q.iter() {
+        // Added components are sent with the entity.
+        if component.is_changed() && !component.is_added() {
+            let data = bincode::serialize(&*component).unwrap();
+            changes
+                .0
+                .push((*server_obj, ReplicatedComponent { id, data }));
+        }
+    }
+}
+
+pub trait AppReplicationExt {
+    /// Replicates `T` when an entity is announced to a client and whenever it changes.
+    fn replicate<T>(&mut self) -> &mut Self
+    where
+        T: Component + Serialize + DeserializeOwned;
+
+    /// Replicates `T` only when an entity is announced to a client, for components whose
+    /// later values are sent in game syncs.
+    fn replicate_on_spawn<T>(&mut self) -> &mut Self
+    where
+        T: Component + Serialize + DeserializeOwned;
+
+    /// Adds a client system that attaches presentation components, such as sprites, to
+    /// replicated entities. Hooks run after replication and query `Added` components.
+    fn add_spawn_hook<M>(&mut self, hook: impl IntoSystemConfigs<M>) -> &mut Self;
+}
+
+impl AppReplicationExt for App {
+    fn replicate<T>(&mut self) -> &mut Self
+    where
+        T: Component + Serialize + DeserializeOwned,
+    {
+        self.replicate_on_spawn::<T>().add_systems(
+            FixedUpdate,
+            collect_changes::<T>
+                .in_set(ServerSchedule::Replication)
+                .run_if(resource_exists::<ReplicationChanges>()),
+        )
+    }
+
+    fn replicate_on_spawn<T>(&mut self) -> &mut Self
+    where
+        T: Component + Serialize + DeserializeOwned,
+    {
+        self.world
+            .get_resource_or_insert_with(ReplicationRegistry::default)
+            .0
+            .push(ComponentFns {
+                name: std::any::type_name::<T>(),
+                serialize: serialize_component::<T>,
+                insert: insert_component::<T>,
+            });
+        self
+    }
+
+    fn add_spawn_hook<M>(&mut self, hook: impl IntoSystemConfigs<M>) -> &mut Self {
+        self.add_systems(
+            FixedUpdate,
+            hook.in_set(ClientSchedule::ServerReactive)
+                .run_if(in_state(ClientState::InGame)),
+        )
+    }
+}
+
+/// Applies replicated spawns, despawns and component changes on the client.
+fn apply_replication(
+    mut commands: Commands,
+    registry: Res<ReplicationRegistry>,
+    mut server_entity_map: ResMut<ServerEntityMap>,
+    mut client: NetClient,
+    mut object_entered: EventReader<FromServer<ObjectEntered>>,
+    mut object_left: EventReader<FromServer<ObjectLeft>>,
+    mut components_changed: EventReader<FromServer<ComponentsChanged>>,
+) {
+    for FromServer {
+        message: ObjectEntered {
+            server_object,
+            components,
+        },
+    } in object_entered.read()
+    {
+        // The object may already have been spawned by a game sync.
+        let entity = match server_entity_map.get(server_object) {
+            Some(entity) => *entity,
+            None => {
+                info!("Spawning replicated server object {:?}", server_object);
+                let entity = commands.spawn((*server_object, Replicated)).id();
+                if let Err(err) = server_entity_map.insert(*server_object, entity) {
+                    error!("Failed to map spawned server object: {}", err);
+                }
+                entity
+            }
+        };
+        registry.insert(&mut commands.entity(entity), components);
+    }
+
+    for FromServer {
+        message: ObjectLeft {
+            server_object,
+            despawned,
+        },
+    } in object_left.read()
+    {
+        if *despawned {
+            client.send(ROMFromClient::DespawnAck(DespawnAck(*server_object)));
+        }
+        let Some(entity) = server_entity_map.remove(server_object) else {
+            continue;
+        };
+        info!("Despawning server object {:?}", server_object);
+        commands.entity(entity).despawn_recursive();
+    }
+
+    for FromServer {
+        message: ComponentsChanged {
+            server_object,
+            components,
+        },
+    } in components_changed.read()
+    {
+        let Some(entity) = server_entity_map.get(server_object) else {
+            continue;
+        };
+        registry.insert(&mut commands.entity(*entity), components);
+    }
+}
+
+/// Registers the replicated components, shared by the server and client.
+pub struct ReplicationPlugin;
+
+impl Plugin for ReplicationPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ReplicationRegistry>()
+            .replicate_on_spawn::<Transform>()
+            .replicate_on_spawn::<Player>();
+    }
+}
+
+pub struct ReplicationPluginServer;
+
+impl Plugin for ReplicationPluginServer {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ReplicationChanges>();
+    }
+}
+
+pub struct ReplicationPluginClient;
+
+impl Plugin for ReplicationPluginClient {
+    fn build(&self, app: &mut App) {
+        app.add_systems(
+            FixedUpdate,
+            apply_replication
+                .in_set(ClientSchedule::ServerEventHandling)
+                .run_if(in_state(ClientState::InGame)),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::system::CommandQueue;
+
+    use super::*;
+
+    /// Replicated whenever it changes, unlike the components the game registers.
+    #[derive(Component, Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+    struct Counter(u32);
+
+    fn app() -> App {
+        let mut app = App::new();
+        app.add_plugins(ReplicationPlugin).replicate::<Counter>();
+        app
+    }
+
+    #[test]
+    fn changed_components_reach_the_client() {
+        let mut server = app();
+        server.add_plugins(ReplicationPluginServer);
+        let server_obj = ServerObject::new(0, 0);
+        let entity = server
+            .world
+            .spawn((server_obj, Replicated, Counter::default()))
+            .id();
+
+        // Added components are sent with the entity, not as changes.
+        server.world.run_schedule(FixedUpdate);
+        let mut changes = server.world.resource_mut::<ReplicationChanges>();
+        assert_eq!(changes.drain().count(), 0);
+
+        server.world.get_mut::<Counter>(entity).unwrap().0 = 2;
+        server.world.run_schedule(FixedUpdate);
+        let components = server
+            .world
+            .resource_mut::<ReplicationChanges>()
+            .drain()
+            .map(|(changed_obj, component)| {
+                assert_eq!(changed_obj, server_obj);
+                component
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(components.len(), 1);
+
+        let mut client = app();
+        let client_entity = client.world.spawn(Counter::default()).id();
+        let mut queue = CommandQueue::default();
+        let mut commands = Commands::new(&mut queue, &client.world);
+        client
+            .world
+            .resource::<ReplicationRegistry>()
+            .insert(&mut commands.entity(client_entity), &components);
+        queue.apply(&mut client.world);
+        assert_eq!(
+            client.world.get::<Counter>(client_entity),
+            Some(&Counter(2))
+        );
+    }
+}
@@ -2,10 +2,15 @@
 /// Rollbacks are only valid after all local and remote input collection and game syncs.
 use bevy::{prelude::*, utils::HashMap};
 use serde::{Deserialize, Serialize};
-use std::{collections::VecDeque, hash::Hash};
+use std::{
+    any::Any,
+    collections::{BTreeSet, VecDeque},
+    hash::Hash,
+};
 
 use crate::{
     game::GameLogic,
+    replication::Replicated,
     schedule::{ClientSchedule, ClientState},
     GameSync, IdPlayerInput, Player, PlayerId, RawPlayerInput, ServerEntityMap,
 };
@@ -32,6 +37,11 @@ impl SyncFrameCount {
 }
 
 pub const DEFAULT_ROLLBACK_WINDOW: usize = 10;
+/// How many frames behind the server a client input may be stamped and still be applied on
+/// its own frame. Must stay below the rollback window.
+pub const MAX_INPUT_LATENESS_FRAMES: u64 = 4;
+/// How many frames ahead of the server a client input may be stamped before it is rejected.
+pub const MAX_INPUT_LEAD_FRAMES: u64 = 4;
 
 #[derive(Debug, Resource)]
 pub struct RollbackTracker<K: Eq + Hash, V> {
@@ -87,6 +97,14 @@ impl<K: Eq + Hash, V> RollbackTracker<K, V> {
         self.get_n_frames_ago(0)
     }
 
+    /// Value of `key` at `frame`, if the frame is still in the history.
+    pub fn get_value_at_frame(&self, key: &K, frame: u64) -> Option<&V> {
+        if frame > self.current_frame {
+            return None;
+        }
+        self.get_at_frame(frame).and_then(|map| map.get(key))
+    }
+
     pub fn get_rollback_window(&self) -> usize {
         self.rollback_window
     }
@@ -109,6 +127,16 @@ impl<K: Eq + Hash, V> RollbackTracker<K, V> {
             .get_mut((self.current_frame - frame) as usize)
             .and_then(|map| map.insert(key, value));
     }
+
+    fn get_mut_at_frame(&mut self, frame: u64) -> Option<&mut HashMap<K, V>> {
+        assert!(
+            self.current_frame >= frame,
+            "Cannot get value at frame. frame = {}, current_frame = {}",
+            frame,
+            self.current_frame
+        );
+        self.history.get_mut((self.current_frame - frame) as usize)
+    }
 }
 
 trait ComponentRollback: Sync + Send {
@@ -119,6 +147,8 @@ trait ComponentRollback: Sync + Send {
     fn rollback_and_update_world(&mut self, frames: u64, world: &mut World);
 
     fn get_current_frame(&self) -> u64;
+
+    fn as_any(&self) -> &dyn Any;
 }
 
 impl<T: Component + Clone + std::fmt::Debug> ComponentRollback for RollbackTracker<Entity, T> {
@@ -135,6 +165,10 @@ impl<T: Component + Clone + std::fmt::Debug> ComponentRollback for RollbackTrack
         self.current_frame
     }
 
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
     /// Syncs `T` in world to current game sync values, rolls back history to game sync frame
     /// and sets the current history frame to the game sync frame.
     fn rollback_and_sync(&mut self, world: &mut World, game_sync: &GameSync) {
@@ -151,8 +185,10 @@ impl<T: Component + Clone + std::fmt::Debug> ComponentRollback for RollbackTrack
                 let entity = match se_map.get(&server_obj) {
                     Some(entity) => *entity,
                     None => {
-                        let entity = world.spawn(*server_obj).id();
-                        se_map.insert(*server_obj, entity).unwrap();
+                        let entity = world.spawn((*server_obj, Replicated)).id();
+                        if let Err(err) = se_map.insert(*server_obj, entity) {
+                            error!("Failed to map spawned server object: {}", err);
+                        }
                         entity
                     }
                 };
@@ -177,10 +213,12 @@ impl<T: Component + Clone + std::fmt::Debug> ComponentRollback for RollbackTrack
         };
 
         for (entity, component) in frame_values.iter() {
-            // @FIXME this can happen with despawns, need to soft delete and hard delete after rollback window has elapsed.
-            let mut entity = world
-                .get_entity_mut(*entity)
-                .expect("Entity in rollback does not exist");
+            // Entities despawned since this frame, e.g. after leaving the area of interest, are
+            // not restored.
+            // @FIXME soft delete and hard delete after rollback window has elapsed.
+            let Some(mut entity) = world.get_entity_mut(*entity) else {
+                continue;
+            };
             entity.insert(component.clone());
         }
     }
@@ -194,34 +232,137 @@ pub struct ComponentRollbacks(Vec<Box<dyn ComponentRollback>>);
 
 impl ComponentRollbacks {
     pub fn from_frame(frame: u64) -> Self {
+        Self::with_window(frame, DEFAULT_ROLLBACK_WINDOW)
+    }
+
+    pub fn with_window(frame: u64, rollback_window: usize) -> Self {
         Self(vec![
-            Box::new(TransformRollback::new(frame, DEFAULT_ROLLBACK_WINDOW)),
-            Box::new(PlayerRollback::new(frame, DEFAULT_ROLLBACK_WINDOW)),
+            Box::new(TransformRollback::new(frame, rollback_window)),
+            Box::new(PlayerRollback::new(frame, rollback_window)),
         ])
     }
+
+    /// Returns the history of component `T`, if it is tracked.
+    pub fn get<T: Component>(&self) -> Option<&RollbackTracker<Entity, T>> {
+        self.0.iter().find_map(|rollback| {
+            rollback
+                .as_any()
+                .downcast_ref::<RollbackTracker<Entity, T>>()
+        })
+    }
 }
 
 #[derive(Resource)]
 pub struct InputRollback {
     tracker: RollbackTracker<PlayerId, RawPlayerInput>,
     future_frames: Vec<IdPlayerInput>,
+    /// Frames each player has already sent input for, used to drop redundant resends.
+    received_frames: HashMap<PlayerId, BTreeSet<u64>>,
+    /// Highest frame up to which every input of each player has been received.
+    contiguous_frames: HashMap<PlayerId, u64>,
 }
 
 impl InputRollback {
     pub fn from_frame(frame: u64) -> Self {
+        Self::with_window(frame, DEFAULT_ROLLBACK_WINDOW)
+    }
+
+    pub fn with_window(frame: u64, rollback_window: usize) -> Self {
         Self {
-            tracker: RollbackTracker::new(frame, DEFAULT_ROLLBACK_WINDOW),
+            tracker: RollbackTracker::new(frame, rollback_window),
             future_frames: Vec::new(),
+            received_frames: HashMap::default(),
+            contiguous_frames: HashMap::default(),
         }
     }
 
-    pub fn accept_input(&mut self, input: IdPlayerInput) {
+    /// Records that input for `frame` was received from `player_id`. Returns `false` if it
+    /// had already been received.
+    pub fn mark_received(&mut self, player_id: PlayerId, frame: u64) -> bool {
+        let frames = self.received_frames.entry(player_id).or_default();
+        if !frames.insert(frame) {
+            return false;
+        }
+
+        let window = self.tracker.rollback_window as u64;
+        let contiguous = self.contiguous_frames.entry(player_id).or_insert(frame);
+        // Gaps older than the rollback window are never resent, so stop waiting for them.
+        if frame > *contiguous + window {
+            *contiguous = frame - window;
+        }
+        while frames.contains(&(*contiguous + 1)) {
+            *contiguous += 1;
+        }
+        true
+    }
+
+    /// Highest frame up to which every input of `player_id` has been received.
+    pub fn contiguous_frame(&self, player_id: &PlayerId) -> Option<u64> {
+        self.contiguous_frames.get(player_id).copied()
+    }
+
+    /// Forgets which inputs were received from `player_id`.
+    pub fn remove_player(&mut self, player_id: &PlayerId) {
+        self.received_frames.remove(player_id);
+        self.contiguous_frames.remove(player_id);
+    }
+
+    /// Multiple inputs from the same player on the same frame are aggregated with
+    /// `RawPlayerInput::aggregate` rather than overwritten. Returns whether the input of a
+    /// simulated frame changed, a missing input counting as an idle one.
+    pub fn accept_input(&mut self, input: IdPlayerInput) -> bool {
         if input.input.frame > self.tracker.current_frame {
             self.future_frames.push(input);
+            false
         } else {
-            self.tracker
-                .set_value_at_frame(input.player_id, input.input.raw, input.input.frame);
+            self.aggregate_input(input)
+        }
+    }
+
+    /// Removes the input of `player_id` at `frame`, returning it if there was one.
+    pub fn remove_input(&mut self, player_id: PlayerId, frame: u64) -> Option<RawPlayerInput> {
+        if frame > self.tracker.current_frame {
+            let mut removed: Option<RawPlayerInput> = None;
+            self.future_frames.retain(|input| {
+                if input.player_id != player_id || input.input.frame != frame {
+                    return true;
+                }
+                match removed.as_mut() {
+                    Some(raw) => raw.aggregate(&input.input.raw),
+                    None => removed = Some(input.input.raw),
+                }
+                false
+            });
+            return removed;
         }
+        self.tracker
+            .get_mut_at_frame(frame)
+            .and_then(|frame_inputs| frame_inputs.remove(&player_id))
+    }
+
+    /// Moves the input of `player_id` from one frame to another.
+    pub fn shift_input(&mut self, player_id: PlayerId, from: u64, to: u64) {
+        if let Some(raw) = self.remove_input(player_id, from) {
+            self.accept_input(IdPlayerInput {
+                player_id,
+                input: raw.at_frame(to),
+            });
+        }
+    }
+
+    fn aggregate_input(&mut self, input: IdPlayerInput) -> bool {
+        let Some(frame_inputs) = self.tracker.get_mut_at_frame(input.input.frame) else {
+            return false;
+        };
+        let previous = frame_inputs
+            .get(&input.player_id)
+            .copied()
+            .unwrap_or_default();
+        let raw = frame_inputs
+            .entry(input.player_id)
+            .and_modify(|raw| raw.aggregate(&input.input.raw))
+            .or_insert(input.input.raw);
+        *raw != previous
     }
 
     fn get_at_frame(&self, frame: u64) -> Option<&HashMap<PlayerId, RawPlayerInput>> {
@@ -242,11 +383,18 @@ impl InputRollback {
         }
 
         for frame in current_frame {
-            self.tracker
-                .set_value_at_frame(frame.player_id, frame.input.raw, frame.input.frame);
+            self.aggregate_input(frame);
         }
 
         self.future_frames = future_frames;
+
+        let oldest_frame = self
+            .tracker
+            .current_frame
+            .saturating_sub(2 * self.tracker.rollback_window as u64);
+        for frames in self.received_frames.values_mut() {
+            *frames = frames.split_off(&oldest_frame);
+        }
     }
 
     pub fn get_latest(&self) -> Option<&HashMap<PlayerId, RawPlayerInput>> {
@@ -391,11 +539,13 @@ fn handle_rollback(world: &mut World) {
                 rollback_frame, frame_count
             );
 
+            // Frames can be left without input when an input is shifted or rejected by the
+            // server, they still need to be resimulated.
             if world.get_resource::<InputRollback>().is_some_and(|ir| {
                 ir.get_at_frame(rollback_frame)
                     .map_or(false, |f| f.is_empty())
             }) {
-                panic!("Rollback frame has no input - why rollback at all?");
+                info!("Rollback frame {} has no input", rollback_frame);
             }
 
             // @TODO don't allow rollbacks that go further back than a game sync.
@@ -445,7 +595,17 @@ impl Plugin for RollbackPluginClient {
     }
 }
 
-pub struct RollbackPluginServer;
+pub struct RollbackPluginServer {
+    pub rollback_window: usize,
+}
+
+impl Default for RollbackPluginServer {
+    fn default() -> Self {
+        Self {
+            rollback_window: DEFAULT_ROLLBACK_WINDOW,
+        }
+    }
+}
 
 impl Plugin for RollbackPluginServer {
     fn build(&self, app: &mut App) {
@@ -453,9 +613,12 @@ impl Plugin for RollbackPluginServer {
 
         let init_frame = 1u64;
         app.insert_resource(SyncFrameCount::new(init_frame));
-        app.insert_resource(ComponentRollbacks::from_frame(init_frame - 1));
+        app.insert_resource(ComponentRollbacks::with_window(
+            init_frame - 1,
+            self.rollback_window,
+        ));
         app.insert_resource(RollbackRequest::default());
-        app.insert_resource(InputRollback::from_frame(init_frame));
+        app.insert_resource(InputRollback::with_window(init_frame, self.rollback_window));
 
         app.add_systems(
             FixedUpdate,
@@ -466,3 +629,75 @@ impl Plugin for RollbackPluginServer {
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PLAYER: PlayerId = PlayerId(1);
+
+    #[test]
+    fn resent_inputs_are_only_received_once() {
+        let mut rollback = InputRollback::with_window(10, 5);
+        assert!(rollback.mark_received(PLAYER, 10));
+        assert!(!rollback.mark_received(PLAYER, 10));
+        assert!(rollback.mark_received(PlayerId(2), 10));
+    }
+
+    #[test]
+    fn contiguous_frame_waits_for_gaps() {
+        let mut rollback = InputRollback::with_window(10, 5);
+        assert_eq!(rollback.contiguous_frame(&PLAYER), None);
+        rollback.mark_received(PLAYER, 10);
+        rollback.mark_received(PLAYER, 11);
+        assert_eq!(rollback.contiguous_frame(&PLAYER), Some(11));
+
+        rollback.mark_received(PLAYER, 13);
+        rollback.mark_received(PLAYER, 14);
+        assert_eq!(rollback.contiguous_frame(&PLAYER), Some(11));
+        rollback.mark_received(PLAYER, 12);
+        assert_eq!(rollback.contiguous_frame(&PLAYER), Some(14));
+    }
+
+    #[test]
+    fn contiguous_frame_skips_gaps_older_than_the_window() {
+        let mut rollback = InputRollback::with_window(10, 5);
+        rollback.mark_received(PLAYER, 10);
+        rollback.mark_received(PLAYER, 20);
+        assert_eq!(rollback.contiguous_frame(&PLAYER), Some(15));
+        for frame in 16..20 {
+            rollback.mark_received(PLAYER, frame);
+        }
+        assert_eq!(rollback.contiguous_frame(&PLAYER), Some(20));
+    }
+
+    #[test]
+    fn removed_players_start_over() {
+        let mut rollback = InputRollback::with_window(10, 5);
+        rollback.mark_received(PLAYER, 10);
+        rollback.remove_player(&PLAYER);
+        assert_eq!(rollback.contiguous_frame(&PLAYER), None);
+        assert!(rollback.mark_received(PLAYER, 10));
+    }
+
+    #[test]
+    fn only_inputs_that_change_a_frame_are_reported() {
+        let mut rollback = InputRollback::with_window(10, 5);
+        rollback.init_current_frame(11);
+        let moving = RawPlayerInput {
+            x_move: 1,
+            ..Default::default()
+        };
+        let at_frame = |raw: RawPlayerInput, frame| IdPlayerInput {
+            player_id: PLAYER,
+            input: raw.at_frame(frame),
+        };
+
+        assert!(!rollback.accept_input(at_frame(RawPlayerInput::default(), 10)));
+        assert!(rollback.accept_input(at_frame(moving, 10)));
+        assert!(!rollback.accept_input(at_frame(moving, 10)));
+        assert!(rollback.accept_input(at_frame(RawPlayerInput::default(), 10)));
+        // Future frames are not simulated yet.
+        assert!(!rollback.accept_input(at_frame(moving, 12)));
+    }
+}
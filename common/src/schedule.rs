@@ -2,9 +2,12 @@ use bevy::prelude::*;
 
 #[derive(Hash, Debug, PartialEq, Eq, Clone, SystemSet)]
 pub enum ServerSchedule {
+    ClientMessageCollection,
     InputHandling,
     Connections,
     Rollback,
+    HitDetection,
+    Replication,
     GameSync,
     Debug,
     FrameUpdate,
@@ -17,9 +20,12 @@ impl Plugin for ServerSchedulePlugin {
         app.configure_sets(
             FixedUpdate,
             (
+                ServerSchedule::ClientMessageCollection,
                 ServerSchedule::InputHandling,
                 ServerSchedule::Connections,
                 ServerSchedule::Rollback,
+                ServerSchedule::HitDetection,
+                ServerSchedule::Replication,
                 ServerSchedule::GameSync,
                 ServerSchedule::Debug,
                 ServerSchedule::FrameUpdate,
@@ -62,5 +68,11 @@ impl Plugin for ClientSchedulePlugin {
 pub enum ClientState {
     #[default]
     MainMenu,
+    /// Logged in, choosing a room and waiting for its match to start.
+    Lobby,
     InGame,
+    /// The connection dropped while in game, the client tries to resume its session.
+    Reconnecting,
+    /// Moving to another instance, the world is rebuilt from the new instance's game sync.
+    Transferring,
 }
@@ -0,0 +1,170 @@
+use bevy::{prelude::*, utils::HashMap};
+use bevy_renet::renet::{ClientId, NetworkInfo, RenetClient};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    channel::{ClientChannel, NetServer, ServerChannel},
+    message::FromServer,
+    schedule::{ClientSchedule, ServerSchedule},
+    UMFromServer,
+};
+
+/// Statistics of a connection as measured by renet.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct LinkStats {
+    /// Smoothed round-trip time in seconds.
+    pub rtt: f32,
+    /// Smoothed change of the round-trip time between ticks in seconds.
+    pub jitter: f32,
+    /// Fraction of packets lost, from 0 to 1.
+    pub packet_loss: f32,
+    pub bytes_sent_per_second: f32,
+    pub bytes_received_per_second: f32,
+}
+
+impl LinkStats {
+    fn update(&mut self, info: &NetworkInfo) {
+        let rtt = info.rtt as f32;
+        // Smoothed like RTP interarrival jitter.
+        self.jitter += ((rtt - self.rtt).abs() - self.jitter) / 16.0;
+        self.rtt = rtt;
+        self.packet_loss = info.packet_loss as f32;
+        self.bytes_sent_per_second = info.bytes_sent_per_second as f32;
+        self.bytes_received_per_second = info.bytes_received_per_second as f32;
+    }
+}
+
+/// Bytes of messages on a channel.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ChannelBytes {
+    pub total: u64,
+    /// Over the last tick.
+    pub per_second: f32,
+    last_total: u64,
+}
+
+impl ChannelBytes {
+    fn update(&mut self, total: u64, delta_seconds: f32) {
+        self.total = total;
+        if delta_seconds > 0.0 {
+            self.per_second = (total - self.last_total) as f32 / delta_seconds;
+        }
+        self.last_total = total;
+    }
+}
+
+/// Network statistics, refreshed every tick.
+#[derive(Resource, Default, Debug)]
+pub struct NetworkStats {
+    /// On the client, the link to the server.
+    pub link: LinkStats,
+    /// On the client, its link as last measured by the server.
+    pub server_link: Option<LinkStats>,
+    /// On the server, the link to each client.
+    pub clients: HashMap<ClientId, LinkStats>,
+    /// Bytes of messages sent on each channel, by channel id.
+    pub sent: HashMap<u8, ChannelBytes>,
+    /// Bytes of messages received on each channel, by channel id. Counted before the
+    /// `LinkConditioner`.
+    pub received: HashMap<u8, ChannelBytes>,
+    sent_totals: HashMap<u8, u64>,
+    received_totals: HashMap<u8, u64>,
+}
+
+impl NetworkStats {
+    pub fn count_sent(&mut self, channel: u8, bytes: usize) {
+        *self.sent_totals.entry(channel).or_default() += bytes as u64;
+    }
+
+    pub fn count_received(&mut self, channel: u8, bytes: usize) {
+        *self.received_totals.entry(channel).or_default() += bytes as u64;
+    }
+
+    fn update_channels(
+        &mut self,
+        sent: impl Iterator<Item = u8>,
+        received: impl Iterator<Item = u8>,
+        delta_seconds: f32,
+    ) {
+        for channel in sent {
+            let total = self.sent_totals.get(&channel).copied().unwrap_or_default();
+            self.sent
+                .entry(channel)
+                .or_default()
+                .update(total, delta_seconds);
+        }
+        for channel in received {
+            let total = self
+                .received_totals
+                .get(&channel)
+                .copied()
+                .unwrap_or_default();
+            self.received
+                .entry(channel)
+                .or_default()
+                .update(total, delta_seconds);
+        }
+    }
+}
+
+/// Refreshes the stats of every client and sends each client its own.
+fn update_server_stats(time: Res<Time>, mut server: NetServer) {
+    let client_ids = server.clients_id();
+    server
+        .stats_mut()
+        .clients
+        .retain(|client_id, _| client_ids.contains(client_id));
+    for client_id in client_ids {
+        let Ok(info) = server.network_info(client_id) else {
+            continue;
+        };
+        let link = server.stats_mut().clients.entry(client_id).or_default();
+        link.update(&info);
+        let link = *link;
+        server.send(client_id, UMFromServer::LinkStats(link));
+    }
+    server.stats_mut().update_channels(
+        ServerChannel::ALL.into_iter().map(u8::from),
+        ClientChannel::ALL.into_iter().map(u8::from),
+        time.delta_seconds(),
+    );
+}
+
+fn update_client_stats(
+    time: Res<Time>,
+    client: Res<RenetClient>,
+    mut stats: ResMut<NetworkStats>,
+    mut server_links: EventReader<FromServer<LinkStats>>,
+) {
+    stats.link.update(&client.network_info());
+    if let Some(FromServer { message }) = server_links.read().last() {
+        stats.server_link = Some(*message);
+    }
+    stats.update_channels(
+        ClientChannel::ALL.into_iter().map(u8::from),
+        ServerChannel::ALL.into_iter().map(u8::from),
+        time.delta_seconds(),
+    );
+}
+
+pub struct NetworkStatsPluginServer;
+
+impl Plugin for NetworkStatsPluginServer {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<NetworkStats>().add_systems(
+            FixedUpdate,
+            update_server_stats.in_set(ServerSchedule::GameSync),
+        );
+    }
+}
+
+pub struct NetworkStatsPluginClient;
+
+impl Plugin for NetworkStatsPluginClient {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<NetworkStats>().add_systems(
+            FixedUpdate,
+            update_client_stats.in_set(ClientSchedule::ServerEventHandling),
+        );
+    }
+}
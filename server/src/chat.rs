@@ -0,0 +1,172 @@
+use bevy::{prelude::*, utils::HashMap};
+use bevy_renet::renet::ClientId;
+use common::{
+    channel::NetServer, message::FromClient, schedule::ServerSchedule, ChatMessage, ChatRequest,
+    ChatScope, ROMFromServer, MAX_CHAT_LENGTH,
+};
+
+use crate::{instance::Instances, interest::CELL_SIZE};
+
+/// Distance within which players receive proximity chat.
+const PROXIMITY_CHAT_DISTANCE: f32 = CELL_SIZE;
+/// Chat messages a client may send per second on average.
+const CHAT_MESSAGES_PER_SECOND: f32 = 0.5;
+/// Chat messages a client may send at once after a quiet period.
+const CHAT_MESSAGE_BURST: f32 = 5.0;
+
+/// Token bucket of chat messages for each client.
+#[derive(Resource, Default)]
+struct ChatRateLimiter(HashMap<ClientId, f32>);
+
+impl ChatRateLimiter {
+    fn refill(&mut self, seconds: f32) {
+        for tokens in self.0.values_mut() {
+            *tokens = (*tokens + CHAT_MESSAGES_PER_SECOND * seconds).min(CHAT_MESSAGE_BURST);
+        }
+    }
+
+    fn allow(&mut self, client_id: ClientId) -> bool {
+        let tokens = self.0.entry(client_id).or_insert(CHAT_MESSAGE_BURST);
+        if *tokens < 1.0 {
+            return false;
+        }
+        *tokens -= 1.0;
+        true
+    }
+}
+
+/// Strips control characters and surrounding whitespace, returning why the text cannot be
+/// sent if it is empty or too long.
+fn sanitize_text(text: &str) -> Result<String, String> {
+    let text = text
+        .chars()
+        .filter(|c| !c.is_control())
+        .collect::<String>()
+        .trim()
+        .to_string();
+    if text.is_empty() {
+        return Err("Message is empty.".to_string());
+    }
+    let length = text.chars().count();
+    if length > MAX_CHAT_LENGTH {
+        return Err(format!(
+            "Message is {} characters long, at most {} are allowed.",
+            length, MAX_CHAT_LENGTH
+        ));
+    }
+    Ok(text)
+}
+
+/// A chat message from the server rather than a player.
+pub fn notice(scope: ChatScope, text: String) -> ROMFromServer {
+    ROMFromServer::Chat(ChatMessage {
+        sender: None,
+        scope,
+        text,
+    })
+}
+
+/// Relays chat messages to the clients in their scope, in every instance but for proximity
+/// chat. Senders also receive their own messages, or a notice of why they were not sent.
+fn relay_chat(
+    time: Res<Time>,
+    mut chat_requests: EventReader<FromClient<ChatRequest>>,
+    mut server: NetServer,
+    mut instances: ResMut<Instances>,
+    mut rate_limiter: ResMut<ChatRateLimiter>,
+) {
+    rate_limiter
+        .0
+        .retain(|client_id, _| instances.client(client_id).is_some());
+    rate_limiter.refill(time.delta_seconds());
+
+    for FromClient {
+        client_id,
+        message: ChatRequest { scope, text },
+    } in chat_requests.read()
+    {
+        let client_id = *client_id;
+        let Some((sender, instance)) = instances.client(&client_id) else {
+            continue;
+        };
+        if !rate_limiter.allow(client_id) {
+            server.send(
+                client_id,
+                notice(*scope, "Too many messages, slow down.".to_string()),
+            );
+            continue;
+        }
+        let text = match sanitize_text(text) {
+            Ok(text) => text,
+            Err(reason) => {
+                server.send(client_id, notice(*scope, reason));
+                continue;
+            }
+        };
+
+        let recipients = match scope {
+            ChatScope::Global => instances
+                .players()
+                .map(|(client_id, _)| client_id)
+                .collect::<Vec<_>>(),
+            ChatScope::Proximity => {
+                let Some(instance) = instances.get_mut(&instance) else {
+                    continue;
+                };
+                let positions = instance
+                    .player_data()
+                    .into_iter()
+                    .map(|player_data| {
+                        (
+                            player_data.player.id,
+                            player_data.transform.translation.truncate(),
+                        )
+                    })
+                    .collect::<HashMap<_, _>>();
+                let Some(origin) = positions.get(&sender).copied() else {
+                    continue;
+                };
+                positions
+                    .iter()
+                    .filter(|(_, position)| position.distance(origin) <= PROXIMITY_CHAT_DISTANCE)
+                    .filter_map(|(player_id, _)| instances.client_of(player_id))
+                    .collect()
+            }
+            ChatScope::Whisper(player_id) => {
+                let Some(recipient) = instances.client_of(player_id) else {
+                    server.send(
+                        client_id,
+                        notice(*scope, format!("Player {} is not online.", player_id)),
+                    );
+                    continue;
+                };
+                if recipient == client_id {
+                    vec![client_id]
+                } else {
+                    vec![recipient, client_id]
+                }
+            }
+        };
+
+        info!("Chat from player {} ({:?}): {}", sender, scope, text);
+        let message = ChatMessage {
+            sender: Some(sender),
+            scope: *scope,
+            text,
+        };
+        for recipient in recipients {
+            server.send(recipient, ROMFromServer::Chat(message.clone()));
+        }
+    }
+}
+
+pub struct ChatPlugin;
+
+impl Plugin for ChatPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ChatRateLimiter>().add_systems(
+            FixedUpdate,
+            relay_chat.in_set(ServerSchedule::InputHandling),
+        );
+    }
+}
@@ -0,0 +1,198 @@
+use std::time::Duration;
+
+use bevy::prelude::*;
+use bevy_rapier2d::prelude::*;
+use common::{
+    rollback::{ComponentRollbacks, SyncFrameCount},
+    schedule::ServerSchedule,
+    stats::{LinkStats, NetworkStats},
+    IdPlayerInput, Player, PlayerId,
+};
+
+use crate::Clients;
+
+/// Furthest back in frames a shot will rewind other players. Must stay below the rollback
+/// window, as older transforms are no longer in the history.
+pub const MAX_LAG_COMPENSATION_FRAMES: u64 = 6;
+
+/// How far a shot travels in pixels.
+pub const SHOT_RANGE: f32 = 500.0;
+
+/// Shooting inputs waiting to be judged, collected as inputs are received.
+#[derive(Resource, Default)]
+pub struct PendingShots(Vec<IdPlayerInput>);
+
+impl PendingShots {
+    pub fn push(&mut self, input: IdPlayerInput) {
+        self.0.push(input);
+    }
+}
+
+/// Most frames a client may claim its view is behind by. Its view of other players lags about
+/// a round trip, the server's measure of it bounds the view delay the client sends.
+fn max_view_delay(link: Option<&LinkStats>, frame_duration: Duration) -> u64 {
+    let Some(link) = link else {
+        return 0;
+    };
+    // One frame of slack for the tick the input and its acknowledgement are handled on.
+    ((link.rtt + link.jitter) / frame_duration.as_secs_f32()).ceil() as u64 + 1
+}
+
+/// Judges shots against other players' transforms as the shooter saw them, rewinding them by
+/// the shooter's view delay as far as its measured round trip allows.
+fn detect_hits(
+    mut pending_shots: ResMut<PendingShots>,
+    component_rollbacks: Res<ComponentRollbacks>,
+    frame_count: Res<SyncFrameCount>,
+    clients: Res<Clients>,
+    stats: Res<NetworkStats>,
+    time: Res<Time<Fixed>>,
+    player_q: Query<(Entity, &Player, &Collider)>,
+) {
+    let Some(transforms) = component_rollbacks.get::<Transform>() else {
+        return;
+    };
+    let current_frame = frame_count.count();
+
+    let (shots, future_shots) = pending_shots
+        .0
+        .drain(..)
+        .partition::<Vec<_>, _>(|shot| shot.input.frame <= current_frame);
+    pending_shots.0 = future_shots;
+
+    for shot in shots {
+        let Some(shooter) = player_q
+            .iter()
+            .find_map(|(entity, player, _)| (player.id == shot.player_id).then_some(entity))
+        else {
+            continue;
+        };
+        let Some(origin) = transforms.get_value_at_frame(&shooter, shot.input.frame) else {
+            warn!(
+                "No transform for player {} on shot frame {}",
+                shot.player_id, shot.input.frame
+            );
+            continue;
+        };
+        let origin = origin.translation.truncate();
+        let direction = Vec2::from_angle(shot.input.raw.x_aim);
+
+        let link = clients
+            .players
+            .iter()
+            .find_map(|(client_id, player_id)| (*player_id == shot.player_id).then_some(*client_id))
+            .and_then(|client_id| stats.clients.get(&client_id));
+        let view_delay =
+            (shot.input.raw.view_delay as u64).min(max_view_delay(link, time.timestep()));
+        let oldest_frame = current_frame.saturating_sub(MAX_LAG_COMPENSATION_FRAMES);
+        let view_frame = shot
+            .input
+            .frame
+            .saturating_sub(view_delay)
+            .max(oldest_frame);
+
+        let mut closest_hit: Option<(f32, PlayerId)> = None;
+        for (entity, player, collider) in player_q.iter() {
+            if entity == shooter {
+                continue;
+            }
+            let Some(radius) = collider.as_ball().map(|ball| ball.radius()) else {
+                continue;
+            };
+            // Players that did not exist on the view frame could not have been seen.
+            let Some(target) = transforms.get_value_at_frame(&entity, view_frame) else {
+                continue;
+            };
+            let Some(distance) =
+                ray_circle_distance(origin, direction, target.translation.truncate(), radius)
+            else {
+                continue;
+            };
+            if !closest_hit.is_some_and(|(closest, _)| closest <= distance) {
+                closest_hit = Some((distance, player.id));
+            }
+        }
+
+        if let Some((_, target)) = closest_hit {
+            info!(
+                "Player {} hit player {} on frame {}, rewound to frame {}",
+                shot.player_id, target, shot.input.frame, view_frame
+            );
+        }
+    }
+}
+
+/// Distance along the ray to the circle, if the ray hits it within `SHOT_RANGE`.
+fn ray_circle_distance(origin: Vec2, direction: Vec2, center: Vec2, radius: f32) -> Option<f32> {
+    let to_center = center - origin;
+    let along = to_center.dot(direction);
+    if !(0.0..=SHOT_RANGE).contains(&along) {
+        return None;
+    }
+    let off_ray_squared = to_center.length_squared() - along * along;
+    (off_ray_squared <= radius * radius).then_some(along)
+}
+
+pub struct CombatPlugin;
+
+impl Plugin for CombatPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<PendingShots>().add_systems(
+            FixedUpdate,
+            detect_hits.in_set(ServerSchedule::HitDetection),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn view_delay_is_bounded_by_the_measured_round_trip() {
+        let frame_duration = Duration::from_millis(50);
+        assert_eq!(max_view_delay(None, frame_duration), 0);
+        let link = LinkStats {
+            rtt: 0.2,
+            jitter: 0.01,
+            ..Default::default()
+        };
+        assert_eq!(max_view_delay(Some(&link), frame_duration), 6);
+    }
+
+    #[test]
+    fn rays_that_pass_the_circle_miss() {
+        assert_eq!(
+            ray_circle_distance(Vec2::ZERO, Vec2::X, Vec2::new(100.0, 17.0), 16.0),
+            None
+        );
+    }
+
+    #[test]
+    fn tangent_rays_hit() {
+        assert_eq!(
+            ray_circle_distance(Vec2::ZERO, Vec2::X, Vec2::new(100.0, 16.0), 16.0),
+            Some(100.0)
+        );
+    }
+
+    #[test]
+    fn rays_from_inside_the_circle_hit() {
+        assert_eq!(
+            ray_circle_distance(Vec2::ZERO, Vec2::X, Vec2::new(4.0, 3.0), 16.0),
+            Some(4.0)
+        );
+    }
+
+    #[test]
+    fn circles_behind_the_origin_or_out_of_range_are_missed() {
+        assert_eq!(
+            ray_circle_distance(Vec2::ZERO, Vec2::X, Vec2::new(-100.0, 0.0), 16.0),
+            None
+        );
+        assert_eq!(
+            ray_circle_distance(Vec2::ZERO, Vec2::X, Vec2::new(SHOT_RANGE + 1.0, 0.0), 16.0),
+            None
+        );
+    }
+}
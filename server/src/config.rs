@@ -0,0 +1,577 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use bevy::{log::Level, prelude::*};
+use clap::Parser;
+use common::{
+    channel::ClientChannel,
+    conditioner::{DirectionConditions, LinkConditioner, LinkConditions},
+    rollback::{DEFAULT_ROLLBACK_WINDOW, MAX_INPUT_LATENESS_FRAMES},
+    FRAME_DURATION_SECONDS, ROOM_MAX_PLAYERS,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::EXTRA_CONNECTIONS;
+
+/// Config file read when no `--config` is given, if it exists.
+const DEFAULT_CONFIG_PATH: &str = "server.ron";
+/// Most clients a netcode server accepts.
+const NETCODE_MAX_CLIENTS: usize = 1024;
+const MAX_TICK_RATE: f64 = 128.0;
+
+/// Options override the config file.
+#[derive(Parser, Debug)]
+pub struct Args {
+    /// RON config file, `server.ron` by default.
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Address the server socket binds to.
+    #[arg(long)]
+    bind_address: Option<SocketAddr>,
+
+    /// Address clients connect to, the bind address by default.
+    #[arg(long)]
+    public_address: Option<SocketAddr>,
+
+    #[arg(long)]
+    max_players: Option<usize>,
+
+    /// Players a room needs, all ready, to start a match.
+    #[arg(long)]
+    min_match_players: Option<usize>,
+
+    /// Seconds between syncs of an object right next to a client.
+    #[arg(long)]
+    sync_interval: Option<f32>,
+
+    /// Frames simulated per second.
+    #[arg(long)]
+    tick_rate: Option<f64>,
+
+    /// Frames of history kept for rollback.
+    #[arg(long)]
+    rollback_window: Option<usize>,
+
+    /// Seconds a disconnected player is kept for its client to reconnect.
+    #[arg(long)]
+    session_grace: Option<f32>,
+
+    /// Seconds clients are warned for before the server shuts down.
+    #[arg(long)]
+    shutdown_countdown: Option<f32>,
+
+    /// RON file players are saved to on shutdown and restored from on login.
+    #[arg(long)]
+    state_path: Option<PathBuf>,
+
+    /// One of error, warn, info, debug or trace.
+    #[arg(long)]
+    log_level: Option<String>,
+
+    /// Key connect tokens are signed with, shared with the `auth` token issuer.
+    #[arg(long)]
+    private_key_path: Option<PathBuf>,
+
+    /// Map to load.
+    #[arg(long)]
+    map: Option<PathBuf>,
+
+    /// Zone every player may move to, repeat for several.
+    #[arg(long = "zone")]
+    zones: Vec<String>,
+
+    /// Simulated latency of messages from clients in milliseconds.
+    #[arg(long)]
+    network_latency: Option<f32>,
+
+    /// Largest random delay added to the simulated latency in milliseconds.
+    #[arg(long)]
+    jitter: Option<f32>,
+
+    /// Chance from 0 to 1 that an unreliable message from a client is dropped.
+    #[arg(long)]
+    packet_loss: Option<f32>,
+
+    /// Chance from 0 to 1 that an unreliable message from a client is delivered twice.
+    #[arg(long)]
+    duplication: Option<f32>,
+
+    /// Chance from 0 to 1 that an unreliable message from a client is delivered late.
+    #[arg(long)]
+    reordering: Option<f32>,
+}
+
+/// Simulated network conditions, see `LinkConditioner`. Times are in milliseconds.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LinkSettings {
+    pub latency: f32,
+    pub jitter: f32,
+    pub packet_loss: f32,
+    pub duplication: f32,
+    pub reordering: f32,
+}
+
+impl LinkSettings {
+    fn conditions(&self) -> LinkConditions {
+        LinkConditions {
+            latency: Duration::from_secs_f32(self.latency / 1000.0),
+            jitter: Duration::from_secs_f32(self.jitter / 1000.0),
+            loss: self.packet_loss,
+            duplication: self.duplication,
+            reordering: self.reordering,
+        }
+    }
+
+    fn validate(&self, field: &'static str) -> Result<(), ConfigError> {
+        for (name, time) in [("latency", self.latency), ("jitter", self.jitter)] {
+            if !(time.is_finite() && time >= 0.0) {
+                return Err(invalid(
+                    field,
+                    format!("{} {} is not a number of milliseconds", name, time),
+                ));
+            }
+        }
+        for (name, chance) in [
+            ("packet_loss", self.packet_loss),
+            ("duplication", self.duplication),
+            ("reordering", self.reordering),
+        ] {
+            if !(0.0..=1.0).contains(&chance) {
+                return Err(invalid(
+                    field,
+                    format!("{} {} is not a chance from 0 to 1", name, chance),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Resource, Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSettings {
+    pub bind_address: SocketAddr,
+    pub public_address: Option<SocketAddr>,
+    pub max_players: usize,
+    pub min_match_players: usize,
+    pub sync_interval: f32,
+    pub tick_rate: f64,
+    pub rollback_window: usize,
+    pub session_grace: f32,
+    pub shutdown_countdown: f32,
+    /// Players are not persisted without one.
+    pub state_path: Option<PathBuf>,
+    pub log_level: String,
+    pub private_key_path: PathBuf,
+    /// Not loaded yet, only checked to exist.
+    pub map: Option<PathBuf>,
+    /// Names of the instances every player may move to, hosted from the start.
+    pub zones: Vec<String>,
+    /// Simulated conditions of messages from clients.
+    pub client_link: LinkSettings,
+    /// Simulated conditions of messages from clients on a channel, by channel id, used instead
+    /// of `client_link`.
+    pub client_channel_links: BTreeMap<u8, LinkSettings>,
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        Self {
+            bind_address: "127.0.0.1:5000".parse().unwrap(),
+            public_address: None,
+            max_players: 64,
+            min_match_players: 2,
+            sync_interval: 1.0,
+            tick_rate: 1.0 / FRAME_DURATION_SECONDS,
+            rollback_window: DEFAULT_ROLLBACK_WINDOW,
+            session_grace: 30.0,
+            shutdown_countdown: 5.0,
+            state_path: None,
+            log_level: "info".to_string(),
+            private_key_path: PathBuf::from("private.key"),
+            map: None,
+            zones: Vec::new(),
+            client_link: LinkSettings::default(),
+            client_channel_links: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read {
+        path: PathBuf,
+        err: std::io::Error,
+    },
+    Parse {
+        path: PathBuf,
+        err: ron::error::SpannedError,
+    },
+    Invalid {
+        field: &'static str,
+        reason: String,
+    },
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Read { path, err } => write!(f, "Failed to read {}: {}", path.display(), err),
+            Self::Parse { path, err } => write!(f, "Failed to parse {}: {}", path.display(), err),
+            Self::Invalid { field, reason } => write!(f, "Invalid {}: {}", field, reason),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+fn invalid(field: &'static str, reason: impl Into<String>) -> ConfigError {
+    ConfigError::Invalid {
+        field,
+        reason: reason.into(),
+    }
+}
+
+impl ServerSettings {
+    /// Reads the config file and applies the command line options on top.
+    pub fn load(args: Args) -> Result<Self, ConfigError> {
+        let mut settings = match &args.config {
+            Some(path) => Self::read(path)?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                Self::read(Path::new(DEFAULT_CONFIG_PATH))?
+            }
+            None => Self::default(),
+        };
+
+        if let Some(bind_address) = args.bind_address {
+            settings.bind_address = bind_address;
+        }
+        if let Some(public_address) = args.public_address {
+            settings.public_address = Some(public_address);
+        }
+        if let Some(max_players) = args.max_players {
+            settings.max_players = max_players;
+        }
+        if let Some(min_match_players) = args.min_match_players {
+            settings.min_match_players = min_match_players;
+        }
+        if let Some(sync_interval) = args.sync_interval {
+            settings.sync_interval = sync_interval;
+        }
+        if let Some(tick_rate) = args.tick_rate {
+            settings.tick_rate = tick_rate;
+        }
+        if let Some(rollback_window) = args.rollback_window {
+            settings.rollback_window = rollback_window;
+        }
+        if let Some(session_grace) = args.session_grace {
+            settings.session_grace = session_grace;
+        }
+        if let Some(shutdown_countdown) = args.shutdown_countdown {
+            settings.shutdown_countdown = shutdown_countdown;
+        }
+        if let Some(state_path) = args.state_path {
+            settings.state_path = Some(state_path);
+        }
+        if let Some(log_level) = args.log_level {
+            settings.log_level = log_level;
+        }
+        if let Some(private_key_path) = args.private_key_path {
+            settings.private_key_path = private_key_path;
+        }
+        if let Some(map) = args.map {
+            settings.map = Some(map);
+        }
+        if !args.zones.is_empty() {
+            settings.zones = args.zones;
+        }
+        if let Some(latency) = args.network_latency {
+            settings.client_link.latency = latency;
+        }
+        if let Some(jitter) = args.jitter {
+            settings.client_link.jitter = jitter;
+        }
+        if let Some(packet_loss) = args.packet_loss {
+            settings.client_link.packet_loss = packet_loss;
+        }
+        if let Some(duplication) = args.duplication {
+            settings.client_link.duplication = duplication;
+        }
+        if let Some(reordering) = args.reordering {
+            settings.client_link.reordering = reordering;
+        }
+
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    fn read(path: &Path) -> Result<Self, ConfigError> {
+        let source = fs::read_to_string(path).map_err(|err| ConfigError::Read {
+            path: path.to_owned(),
+            err,
+        })?;
+        ron::from_str(&source).map_err(|err| ConfigError::Parse {
+            path: path.to_owned(),
+            err,
+        })
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.public_address.is_none() && self.bind_address.ip().is_unspecified() {
+            return Err(invalid(
+                "public_address",
+                format!(
+                    "bind address {} accepts any interface, set the address clients connect to",
+                    self.bind_address
+                ),
+            ));
+        }
+        let max_players = NETCODE_MAX_CLIENTS - EXTRA_CONNECTIONS;
+        if !(1..=max_players).contains(&self.max_players) {
+            return Err(invalid(
+                "max_players",
+                format!("{} is not between 1 and {}", self.max_players, max_players),
+            ));
+        }
+        if !(1..=ROOM_MAX_PLAYERS).contains(&self.min_match_players) {
+            return Err(invalid(
+                "min_match_players",
+                format!(
+                    "{} is not between 1 and {}",
+                    self.min_match_players, ROOM_MAX_PLAYERS
+                ),
+            ));
+        }
+        if !(self.sync_interval.is_finite() && self.sync_interval > 0.0) {
+            return Err(invalid(
+                "sync_interval",
+                format!("{} is not a positive number of seconds", self.sync_interval),
+            ));
+        }
+        if !(self.tick_rate.is_finite() && self.tick_rate > 0.0 && self.tick_rate <= MAX_TICK_RATE)
+        {
+            return Err(invalid(
+                "tick_rate",
+                format!(
+                    "{} is not a positive rate of at most {} frames per second",
+                    self.tick_rate, MAX_TICK_RATE
+                ),
+            ));
+        }
+        if self.rollback_window <= MAX_INPUT_LATENESS_FRAMES as usize {
+            return Err(invalid(
+                "rollback_window",
+                format!(
+                    "{} frames cannot roll back late inputs, it must be over {}",
+                    self.rollback_window, MAX_INPUT_LATENESS_FRAMES
+                ),
+            ));
+        }
+        if !(self.session_grace.is_finite() && self.session_grace >= 0.0) {
+            return Err(invalid(
+                "session_grace",
+                format!("{} is not a number of seconds", self.session_grace),
+            ));
+        }
+        if !(self.shutdown_countdown.is_finite() && self.shutdown_countdown >= 0.0) {
+            return Err(invalid(
+                "shutdown_countdown",
+                format!("{} is not a number of seconds", self.shutdown_countdown),
+            ));
+        }
+        if self.zones.iter().any(|zone| zone.trim().is_empty()) {
+            return Err(invalid("zones", "zone names cannot be empty"));
+        }
+        self.client_link.validate("client_link")?;
+        for (channel, link) in self.client_channel_links.iter() {
+            if !ClientChannel::ALL
+                .iter()
+                .any(|client_channel| u8::from(*client_channel) == *channel)
+            {
+                return Err(invalid(
+                    "client_channel_links",
+                    format!("{} is not a client channel id", channel),
+                ));
+            }
+            link.validate("client_channel_links")?;
+        }
+        self.level()?;
+        if let Some(map) = &self.map {
+            if !map.is_file() {
+                return Err(invalid("map", format!("{} is not a file", map.display())));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn level(&self) -> Result<Level, ConfigError> {
+        self.log_level.parse().map_err(|_| {
+            invalid(
+                "log_level",
+                format!(
+                    "{} is not one of error, warn, info, debug or trace",
+                    self.log_level
+                ),
+            )
+        })
+    }
+
+    pub fn public_address(&self) -> SocketAddr {
+        self.public_address.unwrap_or(self.bind_address)
+    }
+
+    /// Conditions of messages from clients, the server does not condition what it sends.
+    pub fn link_conditioner(&self) -> LinkConditioner {
+        LinkConditioner {
+            client_to_server: DirectionConditions {
+                default: self.client_link.conditions(),
+                channels: self
+                    .client_channel_links
+                    .iter()
+                    .map(|(channel, link)| (*channel, link.conditions()))
+                    .collect(),
+            },
+            ..Default::default()
+        }
+    }
+
+    pub fn frame_duration(&self) -> Duration {
+        Duration::from_secs_f64(1.0 / self.tick_rate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(settings: ServerSettings) -> Option<&'static str> {
+        match settings.validate() {
+            Ok(()) => None,
+            Err(ConfigError::Invalid { field, .. }) => Some(field),
+            Err(err) => panic!("unexpected error: {}", err),
+        }
+    }
+
+    #[test]
+    fn default_settings_are_valid() {
+        assert_eq!(field(ServerSettings::default()), None);
+    }
+
+    #[test]
+    fn unspecified_bind_address_needs_a_public_address() {
+        let mut settings = ServerSettings {
+            bind_address: "0.0.0.0:5000".parse().unwrap(),
+            ..Default::default()
+        };
+        assert_eq!(field(settings.clone()), Some("public_address"));
+        settings.public_address = Some("203.0.113.7:5000".parse().unwrap());
+        assert_eq!(field(settings), None);
+    }
+
+    #[test]
+    fn out_of_range_settings_are_rejected() {
+        let cases = [
+            (
+                ServerSettings {
+                    max_players: 0,
+                    ..Default::default()
+                },
+                "max_players",
+            ),
+            (
+                ServerSettings {
+                    min_match_players: ROOM_MAX_PLAYERS + 1,
+                    ..Default::default()
+                },
+                "min_match_players",
+            ),
+            (
+                ServerSettings {
+                    sync_interval: f32::NAN,
+                    ..Default::default()
+                },
+                "sync_interval",
+            ),
+            (
+                ServerSettings {
+                    tick_rate: MAX_TICK_RATE + 1.0,
+                    ..Default::default()
+                },
+                "tick_rate",
+            ),
+            (
+                ServerSettings {
+                    rollback_window: MAX_INPUT_LATENESS_FRAMES as usize,
+                    ..Default::default()
+                },
+                "rollback_window",
+            ),
+            (
+                ServerSettings {
+                    session_grace: -1.0,
+                    ..Default::default()
+                },
+                "session_grace",
+            ),
+            (
+                ServerSettings {
+                    zones: vec![" ".to_string()],
+                    ..Default::default()
+                },
+                "zones",
+            ),
+            (
+                ServerSettings {
+                    log_level: "loud".to_string(),
+                    ..Default::default()
+                },
+                "log_level",
+            ),
+            (
+                ServerSettings {
+                    map: Some(PathBuf::from("no-such-map.ron")),
+                    ..Default::default()
+                },
+                "map",
+            ),
+            (
+                ServerSettings {
+                    client_link: LinkSettings {
+                        packet_loss: 1.5,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+                "client_link",
+            ),
+            (
+                ServerSettings {
+                    client_channel_links: [(9, LinkSettings::default())].into_iter().collect(),
+                    ..Default::default()
+                },
+                "client_channel_links",
+            ),
+        ];
+        for (settings, expected) in cases {
+            assert_eq!(field(settings), Some(expected));
+        }
+    }
+
+    #[test]
+    fn settings_are_read_from_ron() {
+        let settings: ServerSettings =
+            ron::from_str("(max_players: 16, tick_rate: 10.0, zones: [\"Hub\"])").unwrap();
+        assert_eq!(settings.max_players, 16);
+        assert_eq!(settings.tick_rate, 10.0);
+        assert_eq!(settings.zones, vec!["Hub".to_string()]);
+        assert_eq!(field(settings), None);
+        assert!(ron::from_str::<ServerSettings>("(max_player: 16)").is_err());
+    }
+}
@@ -0,0 +1,416 @@
+use std::collections::BTreeMap;
+
+use bevy::{
+    app::ScheduleRunnerPlugin,
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+use bevy_rapier2d::prelude::*;
+use bevy_renet::renet::{ClientId, RenetServer};
+use common::{
+    bundles::PlayerData,
+    replication::Replicated,
+    rollback::{InputRollback, SyncFrameCount},
+    schedule::ServerSchedule,
+    stats::NetworkStats,
+    GameJoined, GameSync, InstanceId, Player, PlayerId, ServerObject, SessionToken,
+};
+
+use crate::{
+    interest::{Interests, SpatialGrid},
+    objects::ServerObjects,
+    priority::SyncPriorities,
+    session::Sessions,
+    Clients, GamePlugin, RecentInputs,
+};
+
+/// A player spawned in an instance.
+#[derive(Debug, Clone, Copy)]
+pub struct InstancePlayer {
+    pub instance: InstanceId,
+    pub entity: Entity,
+    pub server_object: ServerObject,
+}
+
+/// A game simulated in a world of its own, with its own frames, rollback history and physics.
+pub struct Instance {
+    name: String,
+    /// Players of the match the instance hosts, `None` for a zone open to every player.
+    roster: Option<HashSet<PlayerId>>,
+    world: World,
+}
+
+impl Instance {
+    fn new(name: String, roster: Option<HashSet<PlayerId>>, game: GamePlugin) -> Self {
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins.build().disable::<ScheduleRunnerPlugin>())
+            .add_plugins(game);
+        app.finish();
+        app.cleanup();
+        Self {
+            name,
+            roster,
+            world: std::mem::take(&mut app.world),
+        }
+    }
+
+    #[cfg(feature = "debug")]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn frame(&self) -> u64 {
+        self.world.resource::<SyncFrameCount>().count()
+    }
+
+    #[cfg(feature = "debug")]
+    pub fn world(&self) -> &World {
+        &self.world
+    }
+
+    /// Simulates a frame, sending messages through the server's connections and counting them
+    /// in its stats.
+    fn update(&mut self, server: RenetServer, stats: NetworkStats) -> (RenetServer, NetworkStats) {
+        self.world.insert_resource(server);
+        self.world.insert_resource(stats);
+        self.world.run_schedule(Main);
+        self.world.clear_trackers();
+        (
+            self.world
+                .remove_resource::<RenetServer>()
+                .expect("Instance removed the server"),
+            self.world
+                .remove_resource::<NetworkStats>()
+                .expect("Instance removed the network stats"),
+        )
+    }
+
+    fn spawn_player(&mut self, player_data: PlayerData) -> (Entity, ServerObject) {
+        let server_object = self.world.resource_mut::<ServerObjects>().allocate();
+        let entity = self
+            .world
+            .spawn((server_object, Replicated))
+            .insert((
+                Collider::ball(16.0),
+                RigidBody::KinematicPositionBased,
+                KinematicCharacterController::default(),
+            ))
+            .insert(SpriteBundle {
+                sprite: Sprite {
+                    color: Color::rgb(1.0, 0.0, 0.0),
+                    custom_size: Some(Vec2::new(30.0, 30.0)),
+                    ..Default::default()
+                },
+                ..Default::default()
+            })
+            .insert(player_data)
+            .id();
+        (entity, server_object)
+    }
+
+    fn player(&self, entity: Entity) -> Option<PlayerData> {
+        let entity = self.world.get_entity(entity)?;
+        Some(PlayerData {
+            player: *entity.get::<Player>()?,
+            transform: *entity.get::<Transform>()?,
+        })
+    }
+
+    fn despawn_player(
+        &mut self,
+        entity: Entity,
+        server_object: ServerObject,
+    ) -> Option<PlayerData> {
+        let player_data = self.player(entity);
+        // Clients replicating the player are told it left when interests are updated.
+        if let Some(entity) = self.world.get_entity_mut(entity) {
+            entity.despawn_recursive();
+        }
+        let clients = self
+            .world
+            .resource::<Interests>()
+            .clients_replicating(&server_object)
+            .collect::<Vec<_>>();
+        self.world
+            .resource_mut::<ServerObjects>()
+            .free(server_object, clients);
+        player_data
+    }
+
+    /// Players in the instance, as they would be stored.
+    pub fn player_data(&mut self) -> Vec<PlayerData> {
+        self.world
+            .query::<(&Player, &Transform)>()
+            .iter(&self.world)
+            .map(|(player, transform)| PlayerData {
+                player: *player,
+                transform: *transform,
+            })
+            .collect()
+    }
+
+    /// Adds a client whose player is `server_object`, returning the game sync it starts from.
+    fn join(
+        &mut self,
+        client_id: ClientId,
+        player_id: PlayerId,
+        server_object: ServerObject,
+    ) -> GameSync {
+        // The initial sync only holds what is around the player, other clients pick up a
+        // new player when interests are next updated.
+        let mut transform_q = self.world.query::<(&ServerObject, &Transform)>();
+        let origin = transform_q
+            .iter(&self.world)
+            .find_map(|(server_obj, transform)| {
+                (*server_obj == server_object).then_some(transform.translation)
+            })
+            .unwrap_or_default();
+        let mut objects = self.world.resource::<SpatialGrid>().objects_near(origin);
+        objects.insert(server_object);
+
+        let game_sync = GameSync {
+            transforms: transform_q
+                .iter(&self.world)
+                .filter(|(server_obj, _)| objects.contains(*server_obj))
+                .map(|(server_obj, transform)| (*server_obj, *transform))
+                .collect(),
+            players: self
+                .world
+                .query::<(&ServerObject, &Player)>()
+                .iter(&self.world)
+                .filter(|(server_obj, _)| objects.contains(*server_obj))
+                .map(|(server_obj, player)| (*server_obj, *player))
+                .collect(),
+            frame: self.frame() - 1,
+            unix_time: common::get_unix_time(),
+        };
+        self.world
+            .resource_mut::<Clients>()
+            .players
+            .insert(client_id, player_id);
+        self.world
+            .resource_mut::<Interests>()
+            .insert(client_id, objects);
+        game_sync
+    }
+
+    /// Removes a client and what was kept to sync it, its player stays.
+    fn leave(&mut self, client_id: &ClientId) {
+        let player_id = {
+            let mut clients = self.world.resource_mut::<Clients>();
+            clients.acked_syncs.remove(client_id);
+            clients.sync_histories.remove(client_id);
+            clients.players.remove(client_id)
+        };
+        self.world.resource_mut::<Interests>().remove(client_id);
+        self.world.resource_mut::<ServerObjects>().forget(client_id);
+        self.world
+            .resource_mut::<SyncPriorities>()
+            .remove(client_id);
+        if let Some(player_id) = player_id {
+            self.world.resource_mut::<RecentInputs>().remove(&player_id);
+            self.world
+                .resource_mut::<InputRollback>()
+                .remove_player(&player_id);
+        }
+    }
+}
+
+/// The game instances hosted by the server, and the instance of every client in game. Clients
+/// in the lobby are in no instance.
+#[derive(Resource)]
+pub struct Instances {
+    instances: BTreeMap<InstanceId, Instance>,
+    clients: HashMap<ClientId, (PlayerId, InstanceId)>,
+    next_id: u32,
+    game: GamePlugin,
+}
+
+impl Instances {
+    pub fn new(game: GamePlugin) -> Self {
+        Self {
+            instances: BTreeMap::new(),
+            clients: HashMap::default(),
+            next_id: 0,
+            game,
+        }
+    }
+
+    /// Creates an instance hosting a match, which only its players may enter.
+    pub fn create_match(
+        &mut self,
+        name: String,
+        players: impl IntoIterator<Item = PlayerId>,
+    ) -> InstanceId {
+        self.create(name, Some(players.into_iter().collect()))
+    }
+
+    /// Creates a zone every player may enter, kept open even when empty.
+    pub fn create_zone(&mut self, name: String) -> InstanceId {
+        self.create(name, None)
+    }
+
+    fn create(&mut self, name: String, roster: Option<HashSet<PlayerId>>) -> InstanceId {
+        let id = InstanceId(self.next_id);
+        self.next_id += 1;
+        info!("Creating instance {} ({})", id, name);
+        self.instances
+            .insert(id, Instance::new(name, roster, self.game.clone()));
+        id
+    }
+
+    pub fn contains(&self, id: &InstanceId) -> bool {
+        self.instances.contains_key(id)
+    }
+
+    /// Whether a player may move into an instance: a zone, or the match it plays in.
+    pub fn may_enter(&self, id: &InstanceId, player_id: &PlayerId) -> bool {
+        self.instances
+            .get(id)
+            .is_some_and(|instance| match &instance.roster {
+                Some(roster) => roster.contains(player_id),
+                None => true,
+            })
+    }
+
+    pub fn get_mut(&mut self, id: &InstanceId) -> Option<&mut Instance> {
+        self.instances.get_mut(id)
+    }
+
+    #[cfg(feature = "debug")]
+    pub fn iter(&self) -> impl Iterator<Item = (&InstanceId, &Instance)> {
+        self.instances.iter()
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&InstanceId, &mut Instance)> {
+        self.instances.iter_mut()
+    }
+
+    /// Player and instance of a client in game.
+    pub fn client(&self, client_id: &ClientId) -> Option<(PlayerId, InstanceId)> {
+        self.clients.get(client_id).copied()
+    }
+
+    pub fn client_of(&self, player_id: &PlayerId) -> Option<ClientId> {
+        self.clients
+            .iter()
+            .find_map(|(client_id, (id, _))| (id == player_id).then_some(*client_id))
+    }
+
+    /// Clients in game in every instance, with their players.
+    pub fn players(&self) -> impl Iterator<Item = (ClientId, PlayerId)> + '_ {
+        self.clients
+            .iter()
+            .map(|(client_id, (player_id, _))| (*client_id, *player_id))
+    }
+
+    /// World of the instance a client is in, where its inputs and sync acks are handled.
+    pub fn client_world(&mut self, client_id: &ClientId) -> Option<&mut World> {
+        let (_, id) = self.clients.get(client_id)?;
+        self.instances
+            .get_mut(id)
+            .map(|instance| &mut instance.world)
+    }
+
+    pub fn spawn_player(
+        &mut self,
+        id: InstanceId,
+        player_data: PlayerData,
+    ) -> Option<InstancePlayer> {
+        let (entity, server_object) = self.instances.get_mut(&id)?.spawn_player(player_data);
+        Some(InstancePlayer {
+            instance: id,
+            entity,
+            server_object,
+        })
+    }
+
+    /// State of a spawned player, as it would be stored.
+    pub fn player(&self, player: InstancePlayer) -> Option<PlayerData> {
+        self.instances.get(&player.instance)?.player(player.entity)
+    }
+
+    /// Despawns a player whose session ended, returning its state to be stored.
+    pub fn despawn_player(&mut self, player: InstancePlayer) -> Option<PlayerData> {
+        self.instances
+            .get_mut(&player.instance)?
+            .despawn_player(player.entity, player.server_object)
+    }
+
+    /// Adds a client to the instance of its player, returning the message that starts its game.
+    pub fn join(
+        &mut self,
+        client_id: ClientId,
+        player_id: PlayerId,
+        player: InstancePlayer,
+        session: SessionToken,
+    ) -> Option<GameJoined> {
+        let instance = self.instances.get_mut(&player.instance)?;
+        let game_sync = instance.join(client_id, player_id, player.server_object);
+        self.clients.insert(client_id, (player_id, player.instance));
+        Some(GameJoined {
+            player_id,
+            instance: player.instance,
+            server_object: player.server_object,
+            frame: game_sync.frame + 1,
+            frame_duration: self.game.frame_duration,
+            session,
+            game_sync,
+        })
+    }
+
+    /// Removes a client from its instance, returning its player.
+    pub fn leave(&mut self, client_id: &ClientId) -> Option<PlayerId> {
+        let (player_id, id) = self.clients.remove(client_id)?;
+        if let Some(instance) = self.instances.get_mut(&id) {
+            instance.leave(client_id);
+        }
+        Some(player_id)
+    }
+}
+
+/// Closes the match instances no player has a session in anymore.
+fn close_empty_instances(mut instances: ResMut<Instances>, sessions: Res<Sessions>) {
+    instances.instances.retain(|id, instance| {
+        let used = instance.roster.is_none() || sessions.in_instance(*id);
+        if !used {
+            info!("Closing instance {} ({})", id, instance.name);
+        }
+        used
+    });
+}
+
+/// Simulates a frame of every instance, each taking its turn with the server's connections.
+fn update_instances(world: &mut World) {
+    let Some(mut server) = world.remove_resource::<RenetServer>() else {
+        return;
+    };
+    let mut stats = world.remove_resource::<NetworkStats>().unwrap_or_default();
+    for instance in world.resource_mut::<Instances>().instances.values_mut() {
+        (server, stats) = instance.update(server, stats);
+    }
+    world.insert_resource(server);
+    world.insert_resource(stats);
+}
+
+pub struct InstancePlugin {
+    pub game: GamePlugin,
+    /// Names of the zones hosted from the start.
+    pub zones: Vec<String>,
+}
+
+impl Plugin for InstancePlugin {
+    fn build(&self, app: &mut App) {
+        let mut instances = Instances::new(self.game.clone());
+        for zone in self.zones.iter() {
+            instances.create_zone(zone.clone());
+        }
+        app.insert_resource(instances).add_systems(
+            FixedUpdate,
+            (close_empty_instances, update_instances)
+                .chain()
+                .after(ServerSchedule::Connections)
+                .before(ServerSchedule::GameSync),
+        );
+    }
+}
@@ -0,0 +1,169 @@
+use bevy::{
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+use bevy_renet::renet::ClientId;
+use common::{
+    channel::NetServer,
+    replication::{Replicated, ReplicatedComponent, ReplicationChanges, ReplicationRegistry},
+    ComponentsChanged, ObjectEntered, ObjectLeft, Player, ROMFromServer, ServerObject,
+};
+
+use crate::{objects::ServerObjects, Clients};
+
+/// Width and height of a spatial grid cell in pixels.
+pub const CELL_SIZE: f32 = 256.0;
+/// How many cells around a client's own cell are in its area of interest.
+pub const INTEREST_RADIUS_CELLS: i32 = 2;
+
+/// Server objects bucketed by the grid cell their `Transform` is in. Rebuilt every tick.
+#[derive(Resource, Default)]
+pub struct SpatialGrid {
+    cells: HashMap<IVec2, Vec<ServerObject>>,
+}
+
+impl SpatialGrid {
+    fn cell(translation: Vec3) -> IVec2 {
+        (translation.truncate() / CELL_SIZE).floor().as_ivec2()
+    }
+
+    fn rebuild<'a>(&mut self, objects: impl Iterator<Item = (&'a ServerObject, &'a Transform)>) {
+        self.cells.clear();
+        for (server_obj, transform) in objects {
+            self.cells
+                .entry(Self::cell(transform.translation))
+                .or_default()
+                .push(*server_obj);
+        }
+    }
+
+    /// Server objects in the area of interest around `translation`.
+    pub fn objects_near(&self, translation: Vec3) -> HashSet<ServerObject> {
+        let center = Self::cell(translation);
+        let mut objects = HashSet::default();
+        for x in -INTEREST_RADIUS_CELLS..=INTEREST_RADIUS_CELLS {
+            for y in -INTEREST_RADIUS_CELLS..=INTEREST_RADIUS_CELLS {
+                if let Some(cell) = self.cells.get(&(center + IVec2::new(x, y))) {
+                    objects.extend(cell.iter().copied());
+                }
+            }
+        }
+        objects
+    }
+}
+
+/// Server objects each client currently replicates.
+#[derive(Resource, Default)]
+pub struct Interests(HashMap<ClientId, HashSet<ServerObject>>);
+
+impl Interests {
+    pub fn get(&self, client_id: &ClientId) -> Option<&HashSet<ServerObject>> {
+        self.0.get(client_id)
+    }
+
+    pub fn contains(&self, client_id: &ClientId, server_obj: &ServerObject) -> bool {
+        self.0
+            .get(client_id)
+            .is_some_and(|objects| objects.contains(server_obj))
+    }
+
+    pub fn clients_replicating<'a>(
+        &'a self,
+        server_obj: &'a ServerObject,
+    ) -> impl Iterator<Item = ClientId> + 'a {
+        self.0
+            .iter()
+            .filter(|(_, objects)| objects.contains(server_obj))
+            .map(|(client_id, _)| *client_id)
+    }
+
+    pub fn insert(&mut self, client_id: ClientId, objects: HashSet<ServerObject>) {
+        self.0.insert(client_id, objects);
+    }
+
+    pub fn remove(&mut self, client_id: &ClientId) {
+        self.0.remove(client_id);
+    }
+}
+
+/// Rebuilds the spatial grid and tells clients about replicated server objects entering and
+/// leaving their area of interest.
+#[allow(clippy::too_many_arguments)]
+pub fn update_interests(
+    mut server: NetServer,
+    clients: Res<Clients>,
+    registry: Res<ReplicationRegistry>,
+    mut grid: ResMut<SpatialGrid>,
+    mut interests: ResMut<Interests>,
+    server_objects: Res<ServerObjects>,
+    transform_q: Query<(&ServerObject, &Transform), With<Replicated>>,
+    player_q: Query<(&ServerObject, &Player, &Transform)>,
+    replicated_q: Query<(&ServerObject, EntityRef), With<Replicated>>,
+) {
+    grid.rebuild(transform_q.iter());
+
+    for (client_id, player_id) in clients.players.iter() {
+        let Some((own_obj, _, own_transform)) = player_q
+            .iter()
+            .find(|(_, player, _)| player.id == *player_id)
+        else {
+            continue;
+        };
+        let mut objects = grid.objects_near(own_transform.translation);
+        objects.insert(*own_obj);
+
+        let previous = interests.get(client_id).cloned().unwrap_or_default();
+        for (server_obj, entity) in replicated_q.iter() {
+            if objects.contains(server_obj) && !previous.contains(server_obj) {
+                server.send(
+                    *client_id,
+                    ROMFromServer::ObjectEntered(ObjectEntered {
+                        server_object: *server_obj,
+                        components: registry.serialize(&entity),
+                    }),
+                );
+            }
+        }
+        for server_obj in previous.difference(&objects) {
+            server.send(
+                *client_id,
+                ROMFromServer::ObjectLeft(ObjectLeft {
+                    server_object: *server_obj,
+                    despawned: server_objects.awaits_ack(client_id, server_obj),
+                }),
+            );
+        }
+
+        interests.insert(*client_id, objects);
+    }
+}
+
+/// Sends changes of replicated components to the clients replicating their server object.
+pub fn send_component_changes(
+    mut server: NetServer,
+    clients: Res<Clients>,
+    interests: Res<Interests>,
+    mut changes: ResMut<ReplicationChanges>,
+) {
+    let mut changed_objects: HashMap<ServerObject, Vec<ReplicatedComponent>> = HashMap::default();
+    for (server_obj, component) in changes.drain() {
+        changed_objects
+            .entry(server_obj)
+            .or_default()
+            .push(component);
+    }
+
+    for (server_obj, components) in changed_objects {
+        for client_id in clients.players.keys() {
+            if interests.contains(client_id, &server_obj) {
+                server.send(
+                    *client_id,
+                    ROMFromServer::ComponentsChanged(ComponentsChanged {
+                        server_object: server_obj,
+                        components: components.clone(),
+                    }),
+                );
+            }
+        }
+    }
+}
@@ -1,6 +1,351 @@
-use bevy_renet::renet::ClientId;
-use common::PlayerId;
+use bevy::{prelude::*, utils::HashSet};
+use bevy_renet::renet::{ClientId, ServerEvent};
+use common::{
+    channel::NetServer, message::FromClient, schedule::ServerSchedule, LobbyRejectReason,
+    LobbyRejected, LobbyRequest, LobbyState, PlayerId, ROMFromServer, RoomId, RoomInfo,
+    MAX_ROOM_NAME_LENGTH, ROOM_MAX_PLAYERS,
+};
 
+struct Room {
+    id: RoomId,
+    name: String,
+    /// Clients in the room, in the order they joined.
+    members: Vec<ClientId>,
+    ready: HashSet<ClientId>,
+}
+
+/// Logged in players that are not in a match, and the rooms they gather in. A room starts a
+/// match once it has `min_match_players` players and all of them are ready.
+#[derive(Resource)]
 pub struct Lobby {
     players: Vec<(ClientId, PlayerId)>,
+    rooms: Vec<Room>,
+    next_room_id: u32,
+    min_match_players: usize,
+    /// Whether players must be sent the rooms again.
+    changed: bool,
+}
+
+impl Lobby {
+    pub fn new(min_match_players: usize) -> Self {
+        Self {
+            players: Vec::new(),
+            rooms: Vec::new(),
+            next_room_id: 0,
+            min_match_players,
+            changed: false,
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.players.len()
+    }
+
+    pub fn contains_client(&self, client_id: &ClientId) -> bool {
+        self.players.iter().any(|(id, _)| id == client_id)
+    }
+
+    pub fn contains_player(&self, player_id: &PlayerId) -> bool {
+        self.players.iter().any(|(_, id)| id == player_id)
+    }
+
+    pub fn enter(&mut self, client_id: ClientId, player_id: PlayerId) {
+        self.players.push((client_id, player_id));
+        self.changed = true;
+    }
+
+    /// Removes a client from the lobby and from its room.
+    pub fn leave(&mut self, client_id: &ClientId) {
+        let _ = self.leave_room(client_id);
+        self.players.retain(|(id, _)| id != client_id);
+        self.changed = true;
+    }
+
+    fn player_id(&self, client_id: &ClientId) -> Option<PlayerId> {
+        self.players
+            .iter()
+            .find_map(|(id, player_id)| (id == client_id).then_some(*player_id))
+    }
+
+    fn room_of(&self, client_id: &ClientId) -> Option<usize> {
+        self.rooms
+            .iter()
+            .position(|room| room.members.contains(client_id))
+    }
+
+    /// Creates a room and moves the client into it.
+    fn create_room(
+        &mut self,
+        client_id: ClientId,
+        name: &str,
+    ) -> Result<RoomId, LobbyRejectReason> {
+        let name = name
+            .chars()
+            .filter(|c| !c.is_control())
+            .collect::<String>()
+            .trim()
+            .to_string();
+        if name.is_empty() || name.chars().count() > MAX_ROOM_NAME_LENGTH {
+            return Err(LobbyRejectReason::InvalidRoomName);
+        }
+        let _ = self.leave_room(&client_id);
+        let id = RoomId(self.next_room_id);
+        self.next_room_id += 1;
+        self.rooms.push(Room {
+            id,
+            name,
+            members: vec![client_id],
+            ready: HashSet::default(),
+        });
+        self.changed = true;
+        Ok(id)
+    }
+
+    /// Moves the client into a room, out of the one it was in.
+    fn join_room(&mut self, client_id: ClientId, room_id: RoomId) -> Result<(), LobbyRejectReason> {
+        let room = self
+            .rooms
+            .iter()
+            .find(|room| room.id == room_id)
+            .ok_or(LobbyRejectReason::NoSuchRoom(room_id))?;
+        if room.members.contains(&client_id) {
+            return Ok(());
+        }
+        if room.members.len() >= ROOM_MAX_PLAYERS {
+            return Err(LobbyRejectReason::RoomFull(room_id));
+        }
+        let _ = self.leave_room(&client_id);
+        if let Some(room) = self.rooms.iter_mut().find(|room| room.id == room_id) {
+            room.members.push(client_id);
+        }
+        self.changed = true;
+        Ok(())
+    }
+
+    /// Moves the client out of its room, removing the room once it is empty.
+    fn leave_room(&mut self, client_id: &ClientId) -> Result<(), LobbyRejectReason> {
+        let index = self
+            .room_of(client_id)
+            .ok_or(LobbyRejectReason::NotInRoom)?;
+        let room = &mut self.rooms[index];
+        room.members.retain(|id| id != client_id);
+        room.ready.remove(client_id);
+        if room.members.is_empty() {
+            self.rooms.remove(index);
+        }
+        self.changed = true;
+        Ok(())
+    }
+
+    fn set_ready(&mut self, client_id: ClientId, ready: bool) -> Result<(), LobbyRejectReason> {
+        let index = self
+            .room_of(&client_id)
+            .ok_or(LobbyRejectReason::NotInRoom)?;
+        let room = &mut self.rooms[index];
+        if ready {
+            room.ready.insert(client_id);
+        } else {
+            room.ready.remove(&client_id);
+        }
+        self.changed = true;
+        Ok(())
+    }
+
+    /// Removes the rooms whose players are all ready, and their players, from the lobby,
+    /// returning the name and players of each match to start.
+    pub fn start_matches(&mut self) -> Vec<(String, Vec<(ClientId, PlayerId)>)> {
+        let min_match_players = self.min_match_players;
+        let (started, waiting) = std::mem::take(&mut self.rooms)
+            .into_iter()
+            .partition::<Vec<_>, _>(|room| {
+                room.members.len() >= min_match_players && room.ready.len() == room.members.len()
+            });
+        self.rooms = waiting;
+        if started.is_empty() {
+            return Vec::new();
+        }
+        self.changed = true;
+
+        started
+            .into_iter()
+            .map(|room| {
+                info!(
+                    "Room {} ({}) starts a match with {} players",
+                    room.id,
+                    room.name,
+                    room.members.len()
+                );
+                let players = room
+                    .members
+                    .iter()
+                    .filter_map(|client_id| {
+                        self.player_id(client_id)
+                            .map(|player_id| (*client_id, player_id))
+                    })
+                    .collect::<Vec<_>>();
+                self.players
+                    .retain(|(client_id, _)| !room.members.contains(client_id));
+                (room.name, players)
+            })
+            .collect()
+    }
+
+    fn state_for(&self, client_id: &ClientId) -> LobbyState {
+        LobbyState {
+            rooms: self
+                .rooms
+                .iter()
+                .map(|room| RoomInfo {
+                    id: room.id,
+                    name: room.name.clone(),
+                    players: room
+                        .members
+                        .iter()
+                        .filter_map(|client_id| {
+                            self.player_id(client_id)
+                                .map(|player_id| (player_id, room.ready.contains(client_id)))
+                        })
+                        .collect(),
+                })
+                .collect(),
+            room: self.room_of(client_id).map(|index| self.rooms[index].id),
+            min_match_players: self.min_match_players,
+        }
+    }
+}
+
+fn handle_lobby_requests(
+    mut lobby_requests: EventReader<FromClient<LobbyRequest>>,
+    mut server: NetServer,
+    mut lobby: ResMut<Lobby>,
+) {
+    for FromClient { client_id, message } in lobby_requests.read() {
+        let client_id = *client_id;
+        let result = if !lobby.contains_client(&client_id) {
+            Err(LobbyRejectReason::NotInLobby)
+        } else {
+            match message {
+                LobbyRequest::CreateRoom { name } => lobby
+                    .create_room(client_id, name)
+                    .map(|room_id| info!("Client {} created room {}", client_id, room_id)),
+                LobbyRequest::JoinRoom(room_id) => lobby.join_room(client_id, *room_id),
+                LobbyRequest::LeaveRoom => lobby.leave_room(&client_id),
+                LobbyRequest::SetReady(ready) => lobby.set_ready(client_id, *ready),
+            }
+        };
+        if let Err(reason) = result {
+            warn!(
+                "Rejecting {:?} from client {}: {}",
+                message, client_id, reason
+            );
+            server.send(
+                client_id,
+                ROMFromServer::LobbyRejected(LobbyRejected { reason }),
+            );
+        }
+    }
+}
+
+fn leave_lobby_on_disconnect(
+    mut server_events: EventReader<ServerEvent>,
+    mut lobby: ResMut<Lobby>,
+) {
+    for event in server_events.read() {
+        if let ServerEvent::ClientDisconnected { client_id, .. } = event {
+            if lobby.contains_client(client_id) {
+                lobby.leave(client_id);
+            }
+        }
+    }
+}
+
+/// Sends the rooms to every player in the lobby whenever they change.
+fn send_lobby_states(mut server: NetServer, mut lobby: ResMut<Lobby>) {
+    if !lobby.changed {
+        return;
+    }
+    lobby.changed = false;
+    for (client_id, _) in lobby.players.iter() {
+        server.send(
+            *client_id,
+            ROMFromServer::LobbyState(lobby.state_for(client_id)),
+        );
+    }
+}
+
+pub struct LobbyPlugin {
+    pub min_match_players: usize,
+}
+
+impl Plugin for LobbyPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(Lobby::new(self.min_match_players))
+            .add_systems(
+                FixedUpdate,
+                (
+                    handle_lobby_requests.in_set(ServerSchedule::InputHandling),
+                    leave_lobby_on_disconnect.in_set(ServerSchedule::Connections),
+                    send_lobby_states.in_set(ServerSchedule::GameSync),
+                ),
+            );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lobby(players: u64) -> Lobby {
+        let mut lobby = Lobby::new(2);
+        for id in 0..players {
+            lobby.enter(ClientId::from_raw(id), PlayerId(id));
+        }
+        lobby
+    }
+
+    #[test]
+    fn match_starts_once_every_player_is_ready() {
+        let mut lobby = lobby(3);
+        let (a, b) = (ClientId::from_raw(0), ClientId::from_raw(1));
+        let room_id = lobby.create_room(a, "Room").unwrap();
+        lobby.join_room(b, room_id).unwrap();
+
+        lobby.set_ready(a, true).unwrap();
+        assert!(lobby.start_matches().is_empty());
+
+        lobby.set_ready(b, true).unwrap();
+        let matches = lobby.start_matches();
+        assert_eq!(
+            matches,
+            vec![("Room".to_string(), vec![(a, PlayerId(0)), (b, PlayerId(1))])]
+        );
+        assert!(!lobby.contains_client(&a));
+        assert!(!lobby.contains_client(&b));
+        assert!(lobby.contains_client(&ClientId::from_raw(2)));
+        assert!(lobby.state_for(&a).rooms.is_empty());
+    }
+
+    #[test]
+    fn match_needs_enough_players() {
+        let mut lobby = lobby(1);
+        let a = ClientId::from_raw(0);
+        lobby.create_room(a, "Room").unwrap();
+        lobby.set_ready(a, true).unwrap();
+        assert!(lobby.start_matches().is_empty());
+        assert!(lobby.contains_client(&a));
+    }
+
+    #[test]
+    fn leaving_a_room_unreadies_the_player() {
+        let mut lobby = lobby(2);
+        let (a, b) = (ClientId::from_raw(0), ClientId::from_raw(1));
+        let room_id = lobby.create_room(a, "Room").unwrap();
+        lobby.join_room(b, room_id).unwrap();
+        lobby.set_ready(a, true).unwrap();
+        lobby.set_ready(b, true).unwrap();
+
+        lobby.leave_room(&b).unwrap();
+        lobby.join_room(b, room_id).unwrap();
+        assert!(lobby.start_matches().is_empty());
+    }
 }
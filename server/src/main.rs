@@ -1,262 +1,728 @@
-use bevy::{prelude::*, utils::HashMap};
-use bevy_rapier2d::prelude::*;
+use bevy::{log::LogPlugin, prelude::*, time::TimeUpdateStrategy, utils::HashMap};
 use bevy_renet::{
     renet::{
         transport::{NetcodeServerTransport, ServerAuthentication, ServerConfig},
-        ClientId, ConnectionConfig, DefaultChannel, RenetServer, ServerEvent,
+        ClientId, DisconnectReason, RenetServer, ServerEvent,
     },
     transport::NetcodeServerPlugin,
     RenetServerPlugin,
 };
+use clap::Parser;
+use combat::PendingShots;
 use common::{
     bundles::PlayerData,
+    channel::{connection_config, NetServer},
+    delta::{GameSyncDelta, SyncHistory},
     game::GameLogicPlugin,
-    rollback::{InputRollback, RollbackPluginServer, SyncFrameCount},
+    message::{FromClient, NetMessage, ServerMessagesPlugin},
+    protocol::PROTOCOL_VERSION,
+    replication::{ReplicationPlugin, ReplicationPluginServer},
+    rollback::{
+        InputRollback, RollbackPluginServer, RollbackRequest, SyncFrameCount,
+        MAX_INPUT_LATENESS_FRAMES, MAX_INPUT_LEAD_FRAMES,
+    },
     schedule::{ServerSchedule, ServerSchedulePlugin},
-    GameSync, IdPlayerInput, Player, PlayerId, ROMFromClient, ROMFromServer, ServerObject,
-    UMFromClient, UMFromServer,
+    stats::NetworkStatsPluginServer,
+    ChatScope, GameSync, GameSyncAck, IdPlayerInput, IdPlayerInputs, InputAck, InputsReceived,
+    LoginAccepted, LoginRejectReason, LoginRejected, Player, PlayerId, PlayerInputs, PlayerLogin,
+    ROMFromServer, ServerObject, SnapshotFromServer, TransferRequest, UMFromServer,
+    INPUT_REDUNDANCY,
+};
+use config::ServerSettings;
+use instance::{InstancePlayer, Instances};
+use interest::{send_component_changes, update_interests, Interests, SpatialGrid};
+use lobby::Lobby;
+use messages::{ClientMessages, DecodeErrors};
+use objects::{handle_despawn_acks, ServerObjects};
+use persistence::PlayerStore;
+use priority::{object_size, SyncImportance, SyncPriorities, SYNC_BUDGET_BYTES};
+use session::Sessions;
+use std::{
+    collections::VecDeque,
+    net::UdpSocket,
+    time::{Duration, SystemTime},
 };
-use std::{net::UdpSocket, time::SystemTime};
+use validation::{sanitize_input, InputRateLimiter, Strikes, Violation};
 
 #[cfg(feature = "debug")]
 mod ui;
+mod chat;
+mod combat;
+mod config;
+mod instance;
+mod interest;
 mod lobby;
+mod messages;
+mod objects;
+mod persistence;
+mod priority;
+mod session;
+mod shutdown;
+mod validation;
 
+/// Connections allowed beyond `ServerSettings::max_players`, so that clients over capacity can still be told
+/// why their login was rejected.
+const EXTRA_CONNECTIONS: usize = 4;
+
+/// Clients in game in an instance.
 #[derive(Resource, Default)]
 struct Clients {
     players: HashMap<ClientId, PlayerId>,
+    /// Frame of the most recent game sync each client applied.
+    acked_syncs: HashMap<ClientId, u64>,
+    /// Game syncs sent to each client, which differ by area of interest.
+    sync_histories: HashMap<ClientId, SyncHistory>,
 }
 
-#[derive(Resource)]
-struct GameSyncTimer(Timer);
+/// Most recent inputs applied for each player, relayed together so other clients survive
+/// packet loss.
+#[derive(Resource, Default)]
+struct RecentInputs(HashMap<PlayerId, VecDeque<IdPlayerInput>>);
+
+impl RecentInputs {
+    fn push(&mut self, input: IdPlayerInput) {
+        let inputs = self.0.entry(input.player_id).or_default();
+        inputs.push_back(input);
+        if inputs.len() > INPUT_REDUNDANCY {
+            inputs.pop_front();
+        }
+    }
+
+    fn get(&self, player_id: &PlayerId) -> Vec<IdPlayerInput> {
+        self.0
+            .get(player_id)
+            .map(|inputs| inputs.iter().copied().collect())
+            .unwrap_or_default()
+    }
 
-impl Default for GameSyncTimer {
-    fn default() -> Self {
-        Self(Timer::from_seconds(3.0, TimerMode::Repeating))
+    fn remove(&mut self, player_id: &PlayerId) {
+        self.0.remove(player_id);
     }
 }
 
 fn main() {
+    let settings = ServerSettings::load(config::Args::parse()).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    });
+    let store = PlayerStore::load(settings.state_path.as_deref()).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    });
+    let log_plugin = LogPlugin {
+        level: settings.level().unwrap(),
+        ..Default::default()
+    };
+
     let mut app = App::new();
 
     #[cfg(not(feature = "debug"))]
     {
-        use bevy::{app::ScheduleRunnerPlugin, log::LogPlugin};
-        use std::time::Duration;
+        use bevy::app::ScheduleRunnerPlugin;
 
-        app.add_plugins(LogPlugin::default());
-        app.add_plugins(MinimalPlugins.set(ScheduleRunnerPlugin::run_loop(
-            Duration::from_secs_f32(common::FRAME_DURATION_SECONDS as f32),
-        )));
+        app.add_plugins(log_plugin);
+        app.add_plugins(
+            MinimalPlugins.set(ScheduleRunnerPlugin::run_loop(settings.frame_duration())),
+        );
     }
 
     #[cfg(feature = "debug")]
     {
-        app.add_plugins(DefaultPlugins);
+        app.add_plugins(DefaultPlugins.set(log_plugin));
     }
 
-    app.insert_resource(common::fixed_timestep_rate());
+    app.insert_resource(Time::<Fixed>::from_duration(settings.frame_duration()));
     app.add_plugins(RenetServerPlugin);
-    app.init_resource::<Clients>();
-    app.init_resource::<GameSyncTimer>();
+    app.init_resource::<DecodeErrors>();
+    app.init_resource::<ClientMessages>();
+    app.insert_resource(settings.link_conditioner());
+    app.insert_resource(store);
 
     #[cfg(feature = "debug")]
     app.add_plugins(ui::UIPlugin);
 
     app.add_plugins(ServerSchedulePlugin);
-    app.add_plugins(RollbackPluginServer);
-    app.add_plugins(GameLogicPlugin);
+    app.add_plugins(ServerMessagesPlugin);
+    app.add_plugins(NetworkStatsPluginServer);
+    app.add_plugins(instance::InstancePlugin {
+        game: GamePlugin {
+            frame_duration: settings.frame_duration(),
+            rollback_window: settings.rollback_window,
+            sync_interval_frames: settings.sync_interval * settings.tick_rate as f32,
+        },
+        zones: settings.zones.clone(),
+    });
+    app.add_plugins(chat::ChatPlugin);
+    app.add_plugins(lobby::LobbyPlugin {
+        min_match_players: settings.min_match_players,
+    });
+    app.add_plugins(session::SessionPlugin {
+        grace: Duration::from_secs_f32(settings.session_grace),
+    });
+    app.add_plugins(shutdown::ShutdownPlugin {
+        countdown_seconds: settings.shutdown_countdown,
+    });
 
-    let server = RenetServer::new(ConnectionConfig::default());
+    let server = RenetServer::new(connection_config());
     app.insert_resource(server);
 
     // Transport layer setup
     app.add_plugins(NetcodeServerPlugin);
-    let server_addr = "127.0.0.1:5000".parse().unwrap();
-    let socket = UdpSocket::bind(server_addr).unwrap();
+    let socket = UdpSocket::bind(settings.bind_address)
+        .unwrap_or_else(|err| panic!("Failed to bind to {}: {}", settings.bind_address, err));
+    let private_key = auth::read_private_key(&settings.private_key_path).unwrap_or_else(|err| {
+        panic!(
+            "Failed to read private key from {}: {}. Generate one with `cargo run -p auth -- keygen`",
+            settings.private_key_path.display(),
+            err
+        )
+    });
     let server_config = ServerConfig {
         current_time: SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
             .unwrap(),
-        max_clients: 64,
-        protocol_id: 0,
-        public_addresses: vec![server_addr],
-        authentication: ServerAuthentication::Unsecure,
+        max_clients: settings.max_players + EXTRA_CONNECTIONS,
+        protocol_id: PROTOCOL_VERSION,
+        public_addresses: vec![settings.public_address()],
+        authentication: ServerAuthentication::Secure { private_key },
     };
     let transport = NetcodeServerTransport::new(server_config, socket).unwrap();
     app.insert_resource(transport);
+    app.insert_resource(settings);
 
     app.add_systems(
         FixedUpdate,
         (
-            receive_message_system.in_set(ServerSchedule::InputHandling),
-            handle_events_system.in_set(ServerSchedule::Connections),
-            sync_game.in_set(ServerSchedule::GameSync),
+            messages::receive_messages.in_set(ServerSchedule::ClientMessageCollection),
+            (handle_logins, handle_transfers, handle_despawn_acks)
+                .chain()
+                .in_set(ServerSchedule::InputHandling),
+            (handle_events_system, start_matches)
+                .chain()
+                .in_set(ServerSchedule::Connections),
         ),
     );
     app.run();
 }
 
+/// Simulates and syncs the game of an instance.
+#[derive(Clone)]
+pub struct GamePlugin {
+    pub frame_duration: Duration,
+    pub rollback_window: usize,
+    /// Frames between syncs of an object right next to a client.
+    pub sync_interval_frames: f32,
+}
+
+impl Plugin for GamePlugin {
+    fn build(&self, app: &mut App) {
+        // Instances are updated once per server frame, and simulate exactly one frame each time.
+        app.insert_resource(TimeUpdateStrategy::ManualDuration(self.frame_duration));
+        app.insert_resource(Time::<Fixed>::from_duration(self.frame_duration));
+        app.init_resource::<Clients>();
+        app.init_resource::<RecentInputs>();
+        app.init_resource::<SpatialGrid>();
+        app.init_resource::<Interests>();
+        app.insert_resource(SyncPriorities::new(self.sync_interval_frames));
+        app.init_resource::<ServerObjects>();
+
+        #[cfg(feature = "debug")]
+        app.init_resource::<ui::InputTracker>();
+
+        app.add_plugins(ServerSchedulePlugin);
+        app.add_plugins(ServerMessagesPlugin);
+        app.add_plugins(ReplicationPlugin);
+        app.add_plugins(ReplicationPluginServer);
+        app.add_plugins(RollbackPluginServer {
+            rollback_window: self.rollback_window,
+        });
+        app.add_plugins(GameLogicPlugin);
+        app.add_plugins(combat::CombatPlugin);
+        app.add_plugins(validation::ValidationPlugin);
+
+        app.add_systems(
+            FixedUpdate,
+            (
+                (handle_sync_acks, handle_player_inputs)
+                    .chain()
+                    .in_set(ServerSchedule::InputHandling),
+                (update_interests, send_component_changes, sync_game)
+                    .chain()
+                    .in_set(ServerSchedule::GameSync),
+            ),
+        );
+    }
+}
+
+/// Sends each client a chunk of the game sync with the objects in its area of interest that are
+/// most due for an update, within its bandwidth budget.
 fn sync_game(
-    time: Res<Time>,
-    mut server: ResMut<RenetServer>,
-    mut timer: ResMut<GameSyncTimer>,
-    transform_q: Query<(&ServerObject, &Transform)>,
+    mut server: NetServer,
+    mut clients: ResMut<Clients>,
+    interests: Res<Interests>,
+    mut priorities: ResMut<SyncPriorities>,
+    transform_q: Query<(&ServerObject, &Transform, Option<&SyncImportance>)>,
     player_q: Query<(&ServerObject, &Player)>,
     frame_count: Res<SyncFrameCount>,
 ) {
-    timer.0.tick(time.delta());
-    if timer.0.finished() {
-        info!("Syncing game on frame {}", frame_count.count());
-        let game_sync = GameSync {
-            transforms: transform_q
-                .iter()
-                .map(|(server_obj, transform)| (*server_obj, *transform))
-                .collect(),
-            players: player_q
+    let game_sync = GameSync {
+        transforms: transform_q
+            .iter()
+            .map(|(server_obj, transform, _)| (*server_obj, *transform))
+            .collect(),
+        players: player_q
+            .iter()
+            .map(|(server_obj, player)| (*server_obj, *player))
+            .collect(),
+        frame: frame_count.count(),
+        unix_time: common::get_unix_time(),
+    };
+    let player_objects = player_q
+        .iter()
+        .map(|(server_obj, player)| (player.id, *server_obj))
+        .collect::<HashMap<_, _>>();
+
+    let clients = &mut *clients;
+    for (client_id, player_id) in clients.players.iter() {
+        let Some(objects) = interests.get(client_id) else {
+            continue;
+        };
+        let own_object = player_objects.get(player_id).copied();
+        let origin = own_object
+            .and_then(|server_obj| game_sync.transforms.get(&server_obj))
+            .map(|transform| transform.translation)
+            .unwrap_or_default();
+        let sync_history = clients.sync_histories.entry(*client_id).or_default();
+        // Deltas are encoded against the last view the client applied, if it is still known.
+        let baseline = clients
+            .acked_syncs
+            .get(client_id)
+            .and_then(|frame| sync_history.get(*frame));
+        let encode = |selected: &[ServerObject]| {
+            let client_sync = game_sync.filtered(|server_obj| selected.contains(server_obj));
+            match baseline {
+                Some(baseline) => {
+                    let delta = GameSyncDelta::between(baseline, &client_sync, |server_obj| {
+                        objects.contains(server_obj)
+                    });
+                    let view = delta.apply(baseline);
+                    (SnapshotFromServer::GameSyncDelta(delta), view)
+                }
+                None => (
+                    SnapshotFromServer::GameSync(client_sync.clone()),
+                    client_sync,
+                ),
+            }
+        };
+        let encoded_size =
+            |message: &SnapshotFromServer| message.encode().map_or(usize::MAX, |bytes| bytes.len());
+
+        let mut selected = priorities.select(
+            *client_id,
+            own_object,
+            origin,
+            transform_q
                 .iter()
-                .map(|(server_obj, player)| (*server_obj, *player))
-                .collect(),
-            frame: frame_count.count(),
-            unix_time: common::get_unix_time(),
+                .filter(|(server_obj, _, _)| objects.contains(*server_obj))
+                .map(|(server_obj, transform, importance)| {
+                    (
+                        *server_obj,
+                        transform.translation,
+                        importance.copied().unwrap_or_default(),
+                    )
+                }),
+            |server_obj| object_size(baseline, &game_sync, server_obj),
+            SYNC_BUDGET_BYTES.saturating_sub(encoded_size(&encode(&[]).0)),
+        );
+        // Sizes are estimated per object, the budget holds for the encoded message.
+        let (message, view) = loop {
+            let (message, view) = encode(&selected);
+            if encoded_size(&message) <= SYNC_BUDGET_BYTES || selected.is_empty() {
+                break (message, view);
+            }
+            selected.pop();
         };
-        info!("{:?}", game_sync);
-        server.broadcast_message(
-            DefaultChannel::Unreliable,
-            UMFromServer::GameSync(game_sync),
+        if selected.is_empty() {
+            continue;
+        }
+
+        debug!(
+            "Syncing {} objects to client {} on frame {}",
+            selected.len(),
+            client_id,
+            game_sync.frame
         );
+        priorities.synced(client_id, selected.iter());
+        server.send(*client_id, message);
+        sync_history.push(view);
+    }
+}
+
+/// Decides on which frame, if any, an input stamped by a client is applied.
+fn ack_for_input(frame: u64, current_frame: u64) -> InputAck {
+    if frame > current_frame + MAX_INPUT_LEAD_FRAMES {
+        InputAck::Rejected { frame }
+    } else if frame + MAX_INPUT_LATENESS_FRAMES < current_frame {
+        InputAck::Shifted {
+            from: frame,
+            to: current_frame,
+        }
+    } else {
+        InputAck::Accepted { frame }
+    }
+}
+
+/// Decides whether a client may log in, returning the player id bound to its connect token.
+fn validate_login(
+    login: &PlayerLogin,
+    client_id: ClientId,
+    transport: &NetcodeServerTransport,
+    instances: &Instances,
+    lobby: &Lobby,
+    sessions: &Sessions,
+    max_players: usize,
+) -> Result<PlayerId, LoginRejectReason> {
+    if login.protocol_version != PROTOCOL_VERSION {
+        return Err(LoginRejectReason::ProtocolMismatch {
+            server_version: PROTOCOL_VERSION,
+            client_version: login.protocol_version,
+        });
+    }
+    if instances.client(&client_id).is_some() || lobby.contains_client(&client_id) {
+        return Err(LoginRejectReason::AlreadyLoggedIn);
+    }
+    // The player id comes from the connect token, which the client cannot forge.
+    let Some(player_id) = transport
+        .user_data(client_id)
+        .map(|user_data| auth::player_id_from_user_data(&user_data))
+    else {
+        return Err(LoginRejectReason::NotAuthenticated);
+    };
+    if instances.client_of(&player_id).is_some() || lobby.contains_player(&player_id) {
+        return Err(LoginRejectReason::PlayerIdInUse(player_id));
     }
+    if let Some(token) = login.resume {
+        if !sessions.can_resume(&player_id, token) {
+            return Err(LoginRejectReason::SessionExpired);
+        }
+    }
+    // Disconnected players count until their session expires, a player logging in again
+    // takes over its own session.
+    if !sessions.contains(&player_id) && sessions.count() + lobby.count() >= max_players {
+        return Err(LoginRejectReason::ServerFull { max_players });
+    }
+    Ok(player_id)
 }
 
-fn receive_message_system(
-    mut commands: Commands,
-    mut server: ResMut<RenetServer>,
+fn handle_sync_acks(
+    mut sync_acks: EventReader<FromClient<GameSyncAck>>,
     mut clients: ResMut<Clients>,
-    transform_q: Query<(&ServerObject, &Transform)>,
+) {
+    for FromClient {
+        client_id,
+        message: GameSyncAck(frame),
+    } in sync_acks.read()
+    {
+        let acked_frame = clients.acked_syncs.entry(*client_id).or_insert(*frame);
+        *acked_frame = (*frame).max(*acked_frame);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn handle_player_inputs(
+    mut player_inputs: EventReader<FromClient<PlayerInputs>>,
+    mut server: NetServer,
+    clients: Res<Clients>,
     player_q: Query<(&ServerObject, &Player)>,
     mut input_rollback: ResMut<InputRollback>,
+    mut rollback_request: ResMut<RollbackRequest>,
+    mut recent_inputs: ResMut<RecentInputs>,
+    mut pending_shots: ResMut<PendingShots>,
+    interests: Res<Interests>,
     frame_count: Res<SyncFrameCount>,
+    mut strikes: ResMut<Strikes>,
+    mut rate_limiter: ResMut<InputRateLimiter>,
     #[cfg(feature = "debug")] mut input_tracker: ResMut<self::ui::InputTracker>,
 ) {
-    for client_id in server.clients_id() {
-        while let Some(message) = server.receive_message(client_id, DefaultChannel::Unreliable) {
-            let Ok(client_message) = UMFromClient::try_from(message) else {
-                warn!("Failed to deserialize client event");
+    rate_limiter.tick();
+    for FromClient {
+        client_id,
+        message: PlayerInputs(framed_inputs),
+    } in player_inputs.read()
+    {
+        let client_id = *client_id;
+        let Some(player_id) = clients.players.get(&client_id).copied() else {
+            warn!("Client {} not logged in", client_id);
+            continue;
+        };
+        if !rate_limiter.allow(client_id) {
+            strikes.add(client_id, Violation::InputFlood);
+            continue;
+        }
+
+        let current_frame = frame_count.count();
+        let mut had_new_input = false;
+        for framed_input in framed_inputs.iter().take(INPUT_REDUNDANCY) {
+            // Inputs are repeated across packets until acknowledged.
+            if !input_rollback.mark_received(player_id, framed_input.frame) {
+                continue;
+            }
+            let mut raw = framed_input.raw;
+            if let Err(violation) = sanitize_input(&mut raw) {
+                strikes.add(client_id, violation);
+                if violation.rejects_input() {
+                    continue;
+                }
+            }
+
+            let ack = ack_for_input(framed_input.frame, current_frame);
+            if !matches!(ack, InputAck::Accepted { .. }) {
+                server.send(client_id, UMFromServer::InputAck(ack));
+            }
+
+            let Some(frame) = ack.applied_frame() else {
+                warn!(
+                    "Rejecting input from client {} for frame {}, current frame is {}",
+                    client_id, framed_input.frame, current_frame
+                );
                 continue;
             };
+            info!("Accepting input for frame {}", frame);
+
+            #[cfg(feature = "debug")]
+            input_tracker
+                .inputs
+                .entry(player_id)
+                .and_modify(|e| *e += 1)
+                .or_insert(1);
+
+            let id_input = IdPlayerInput {
+                player_id,
+                input: raw.at_frame(frame),
+            };
+            // Clients send idle inputs too, only a change to a past frame needs a rollback.
+            let changed = input_rollback.accept_input(id_input);
+            if id_input.input.raw.shoot {
+                pending_shots.push(id_input);
+            }
+            if changed && frame < current_frame {
+                rollback_request.request(frame);
+            }
+            recent_inputs.push(id_input);
+            had_new_input = true;
+        }
 
-            match client_message {
-                UMFromClient::PlayerInput(raw_input) => {
-                    let Some(player_id) = clients.players.get(&client_id) else {
-                        warn!("Client {} not logged in", client_id);
-                        continue;
-                    };
-                    info!("Accepting input");
-
-                    #[cfg(feature = "debug")]
-                    input_tracker
-                        .inputs
-                        .entry(*player_id)
-                        .and_modify(|e| *e += 1)
-                        .or_insert(1);
-
-                    let id_input = IdPlayerInput {
-                        player_id: *player_id,
-                        input: raw_input.at_frame(frame_count.count()),
-                    };
-                    input_rollback.accept_input(id_input);
-                    server.broadcast_message_except(
-                        client_id,
-                        DefaultChannel::Unreliable,
-                        UMFromServer::IdPlayerInput(id_input),
+        // Only clients replicating the player need its inputs.
+        let player_obj = player_q
+            .iter()
+            .find_map(|(server_obj, player)| (player.id == player_id).then_some(*server_obj));
+        if let (true, Some(player_obj)) = (had_new_input, player_obj) {
+            let inputs = recent_inputs.get(&player_id);
+            for other_id in clients.players.keys() {
+                if *other_id != client_id && interests.contains(other_id, &player_obj) {
+                    server.send(
+                        *other_id,
+                        UMFromServer::IdPlayerInputs(IdPlayerInputs(inputs.clone())),
                     );
                 }
             }
         }
+    }
 
-        while let Some(message) = server.receive_message(client_id, DefaultChannel::ReliableOrdered)
-        {
-            let Ok(client_message) = ROMFromClient::try_from(message) else {
-                warn!("Failed to deserialize client event");
-                continue;
-            };
+    for (client_id, player_id) in clients.players.iter() {
+        if let Some(frame) = input_rollback.contiguous_frame(player_id) {
+            server.send(
+                *client_id,
+                UMFromServer::InputsReceived(InputsReceived(frame)),
+            );
+        }
+    }
+}
 
-            #[allow(irrefutable_let_patterns)] // there will be more.
-            let ROMFromClient::PlayerLogin(login) = client_message
-            else {
-                warn!("Unexpected client message");
-                continue;
-            };
+/// Starts a session for a player entering an instance and sends it the instance's game sync.
+fn join_instance(
+    server: &mut NetServer,
+    instances: &mut Instances,
+    sessions: &mut Sessions,
+    (client_id, player_id): (ClientId, PlayerId),
+    player: InstancePlayer,
+) {
+    // Every login issues a new session token, so an old one cannot be replayed.
+    let session = sessions.start(player_id, player);
+    match instances.join(client_id, player_id, player, session) {
+        Some(game_joined) => server.send(client_id, ROMFromServer::GameJoined(game_joined)),
+        None => warn!(
+            "Player {} cannot join closed instance {}",
+            player_id, player.instance
+        ),
+    }
+}
 
-            info!("Player trying to login");
+/// Puts logged in players in the lobby, or back in their instance if they resume their session.
+fn handle_logins(
+    mut logins: EventReader<FromClient<PlayerLogin>>,
+    mut server: NetServer,
+    transport: Res<NetcodeServerTransport>,
+    mut instances: ResMut<Instances>,
+    mut lobby: ResMut<Lobby>,
+    mut sessions: ResMut<Sessions>,
+    settings: Res<ServerSettings>,
+) {
+    for FromClient {
+        client_id,
+        message: login,
+    } in logins.read()
+    {
+        let client_id = *client_id;
+        info!("Player trying to login");
 
-            if clients.players.contains_key(&client_id) {
-                warn!("Client {} already logged in", client_id);
+        let player_id = match validate_login(
+            login,
+            client_id,
+            &transport,
+            &instances,
+            &lobby,
+            &sessions,
+            settings.max_players,
+        ) {
+            Ok(player_id) => player_id,
+            Err(reason) => {
+                warn!("Rejecting login from client {}: {}", client_id, reason);
+                server.send(
+                    client_id,
+                    ROMFromServer::LoginRejected(LoginRejected { reason }),
+                );
                 continue;
             }
-            clients.players.insert(client_id, login.id);
+        };
 
-            let server_object = ServerObject::rand();
-            let player_data = PlayerData {
+        if let Some(player) = login.resume.and_then(|_| sessions.resume(&player_id)) {
+            info!("Resuming session of player {}", player_id);
+            join_instance(
+                &mut server,
+                &mut instances,
+                &mut sessions,
+                (client_id, player_id),
+                player,
+            );
+            continue;
+        }
+
+        // A new login replaces the player of a session that was not resumed.
+        if let Some(player) = sessions.end(&player_id) {
+            instances.despawn_player(player);
+        }
+        info!("Accepting login of player {}", player_id);
+        server.send(
+            client_id,
+            ROMFromServer::LoginAccepted(LoginAccepted { player_id }),
+        );
+        lobby.enter(client_id, player_id);
+    }
+}
+
+/// Opens an instance for every room that is ready to start a match, and spawns its players
+/// where they were when they last left the game.
+fn start_matches(
+    mut server: NetServer,
+    mut lobby: ResMut<Lobby>,
+    mut instances: ResMut<Instances>,
+    mut sessions: ResMut<Sessions>,
+    mut store: ResMut<PlayerStore>,
+) {
+    for (name, players) in lobby.start_matches() {
+        let instance =
+            instances.create_match(name, players.iter().map(|(_, player_id)| *player_id));
+        for (client_id, player_id) in players {
+            let player_data = store.take(&player_id).unwrap_or(PlayerData {
                 player: Player {
-                    id: login.id,
+                    id: player_id,
                     ..Default::default()
                 },
                 transform: Transform::default(),
+            });
+            let Some(player) = instances.spawn_player(instance, player_data) else {
+                continue;
             };
-
-            info!("Sending connection game sync");
-            server.send_message(
-                client_id,
-                DefaultChannel::ReliableOrdered,
-                ROMFromServer::GameSync(GameSync {
-                    transforms: transform_q
-                        .iter()
-                        .chain(std::iter::once((&server_object, &player_data.transform)))
-                        .map(|(server_obj, transform)| (*server_obj, *transform))
-                        .collect(),
-                    players: player_q
-                        .iter()
-                        .chain(std::iter::once((&server_object, &player_data.player)))
-                        .map(|(server_obj, player)| (*server_obj, *player))
-                        .collect(),
-                    frame: frame_count.count() - 1,
-                    unix_time: common::get_unix_time(),
-                }),
-            );
-            server.broadcast_message(
-                DefaultChannel::ReliableOrdered,
-                ROMFromServer::PlayerConnected {
-                    player_data,
-                    server_object,
-                },
+            join_instance(
+                &mut server,
+                &mut instances,
+                &mut sessions,
+                (client_id, player_id),
+                player,
             );
-            commands
-                .spawn(server_object)
-                .insert(player_data)
-                .insert((
-                    Collider::ball(16.0),
-                    RigidBody::KinematicPositionBased,
-                    KinematicCharacterController::default(),
-                ))
-                .insert(SpriteBundle {
-                    sprite: Sprite {
-                        color: Color::rgb(1.0, 0.0, 0.0),
-                        custom_size: Some(Vec2::new(30.0, 30.0)),
-                        ..Default::default()
-                    },
-                    ..Default::default()
-                });
         }
     }
 }
 
+/// Moves players to the zones and to their own match instances. The player is only despawned
+/// from its instance once it is spawned in the other one.
+fn handle_transfers(
+    mut transfer_requests: EventReader<FromClient<TransferRequest>>,
+    mut server: NetServer,
+    mut instances: ResMut<Instances>,
+    mut sessions: ResMut<Sessions>,
+) {
+    for FromClient {
+        client_id,
+        message: TransferRequest { instance },
+    } in transfer_requests.read()
+    {
+        let client_id = *client_id;
+        let Some((player_id, current)) = instances.client(&client_id) else {
+            continue;
+        };
+        let refusal = if *instance == current {
+            Some(format!("Already in instance {}.", instance))
+        } else if !instances.contains(instance) {
+            Some(format!("Instance {} does not exist.", instance))
+        } else if !instances.may_enter(instance, &player_id) {
+            Some(format!("Instance {} hosts another match.", instance))
+        } else {
+            None
+        };
+        if let Some(refusal) = refusal {
+            server.send(client_id, chat::notice(ChatScope::Global, refusal));
+            continue;
+        }
+
+        // Nothing changes until the player is spawned in the other instance.
+        let Some((player, moved)) = sessions.player(&player_id).and_then(|player| {
+            let player_data = instances.player(player)?;
+            Some((player, instances.spawn_player(*instance, player_data)?))
+        }) else {
+            warn!(
+                "Player {} could not be moved to instance {}",
+                player_id, instance
+            );
+            let refusal = format!("Could not move to instance {}.", instance);
+            server.send(client_id, chat::notice(ChatScope::Global, refusal));
+            continue;
+        };
+        info!(
+            "Moving player {} from instance {} to {}",
+            player_id, current, instance
+        );
+        instances.despawn_player(player);
+        instances.leave(&client_id);
+        // The new session replaces the one in the old instance.
+        join_instance(
+            &mut server,
+            &mut instances,
+            &mut sessions,
+            (client_id, player_id),
+            moved,
+        );
+    }
+}
+
 fn handle_events_system(
-    mut commands: Commands,
-    player_q: Query<(Entity, &Player)>,
+    time: Res<Time>,
     mut server_events: EventReader<ServerEvent>,
-    mut server: ResMut<RenetServer>,
-    mut clients: ResMut<Clients>,
+    mut instances: ResMut<Instances>,
+    mut decode_errors: ResMut<DecodeErrors>,
+    mut client_messages: ResMut<ClientMessages>,
+    mut sessions: ResMut<Sessions>,
 ) {
     for event in server_events.read() {
         match event {
@@ -264,19 +730,23 @@ fn handle_events_system(
                 info!("Client {client_id} connected");
             }
             ServerEvent::ClientDisconnected { client_id, reason } => {
-                info!("Client {client_id} disconnected: {reason}");
-                let Some(player_id) = clients.players.remove(client_id) else {
+                info!(
+                    "Client {client_id} disconnected: {reason} ({} decode errors)",
+                    decode_errors.get(client_id)
+                );
+                decode_errors.remove(client_id);
+                client_messages.remove_sender(client_id);
+                let Some(player_id) = instances.leave(client_id) else {
                     continue;
                 };
-                for (entity, player) in player_q.iter() {
-                    if player.id == player_id {
-                        commands.entity(entity).despawn_recursive();
-                    }
+                if matches!(reason, DisconnectReason::DisconnectedByServer) {
+                    // The server only disconnects clients it kicks, which must not reconnect
+                    // to a clean record.
+                    sessions.revoke(&player_id, time.elapsed());
+                } else {
+                    // The player stays in its instance until its session expires.
+                    sessions.disconnect(&player_id, time.elapsed());
                 }
-                server.broadcast_message(
-                    DefaultChannel::ReliableOrdered,
-                    ROMFromServer::PlayerDisconnected(player_id),
-                );
             }
         }
     }
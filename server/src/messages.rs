@@ -0,0 +1,93 @@
+use std::time::Instant;
+
+use bevy::{prelude::*, utils::HashMap};
+use bevy_renet::renet::{ClientId, RenetServer};
+use common::{
+    conditioner::{ConditionedMessages, LinkConditioner},
+    message::ClientMessageEvents,
+    stats::NetworkStats,
+    ROMFromClient, UMFromClient,
+};
+
+use crate::instance::Instances;
+
+/// Number of messages from each client that could not be decoded.
+#[derive(Resource, Default)]
+pub struct DecodeErrors(HashMap<ClientId, u32>);
+
+impl DecodeErrors {
+    pub fn get(&self, client_id: &ClientId) -> u32 {
+        self.0.get(client_id).copied().unwrap_or_default()
+    }
+
+    fn count(&mut self, client_id: ClientId) -> u32 {
+        let errors = self.0.entry(client_id).or_default();
+        *errors += 1;
+        *errors
+    }
+
+    pub fn remove(&mut self, client_id: &ClientId) {
+        self.0.remove(client_id);
+    }
+}
+
+/// Messages from clients delayed by the `LinkConditioner`.
+pub type ClientMessages = ConditionedMessages<ClientId>;
+
+/// Decodes messages from every client, once the `LinkConditioner` delivers them, into
+/// `FromClient` events. Inputs and sync acks are sent to the world of the client's instance.
+pub fn receive_messages(world: &mut World) {
+    let now = Instant::now();
+    receive::<UMFromClient>(world, now, true);
+    receive::<ROMFromClient>(world, now, false);
+}
+
+fn receive<M: ClientMessageEvents>(world: &mut World, now: Instant, to_instance: bool) {
+    let channel = M::CHANNEL.into();
+    let conditions = *world
+        .resource::<LinkConditioner>()
+        .direction(M::DIRECTION)
+        .get(channel);
+    world.resource_scope(|world, mut server: Mut<RenetServer>| {
+        let mut received = 0;
+        let mut client_messages = world.resource_mut::<ClientMessages>();
+        for client_id in server.clients_id() {
+            while let Some(bytes) = server.receive_message(client_id, channel) {
+                received += bytes.len();
+                client_messages.push(
+                    &conditions,
+                    M::CHANNEL.is_reliable(),
+                    client_id,
+                    channel,
+                    bytes,
+                    now,
+                );
+            }
+        }
+        world
+            .resource_mut::<NetworkStats>()
+            .count_received(channel, received);
+    });
+
+    let messages = world.resource_mut::<ClientMessages>().deliver(channel, now);
+    for (client_id, bytes) in messages {
+        match M::decode(&bytes) {
+            Ok(message) if to_instance => {
+                // Clients in the lobby have no game to send to.
+                if let Some(instance_world) =
+                    world.resource_mut::<Instances>().client_world(&client_id)
+                {
+                    message.send_events(client_id, instance_world);
+                }
+            }
+            Ok(message) => message.send_events(client_id, world),
+            Err(err) => {
+                let errors = world.resource_mut::<DecodeErrors>().count(client_id);
+                warn!(
+                    "Failed to decode message from client {} ({} errors): {}",
+                    client_id, errors, err
+                );
+            }
+        }
+    }
+}
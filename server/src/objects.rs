@@ -0,0 +1,163 @@
+use std::collections::VecDeque;
+
+use bevy::{
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+use bevy_renet::renet::ClientId;
+use common::{message::FromClient, DespawnAck, ServerObject};
+
+use crate::instance::Instances;
+
+/// Allocates `ServerObject` ids. New indices increase monotonically, a despawned index is only
+/// reused with the next generation once every client replicating the object acknowledged the
+/// despawn.
+#[derive(Resource, Default)]
+pub struct ServerObjects {
+    next_index: u32,
+    /// Ids ready to be reused, with their generation already advanced.
+    free: VecDeque<ServerObject>,
+    /// Despawned ids and the clients yet to acknowledge the despawn.
+    despawned: HashMap<ServerObject, HashSet<ClientId>>,
+}
+
+impl ServerObjects {
+    pub fn allocate(&mut self) -> ServerObject {
+        if let Some(server_object) = self.free.pop_front() {
+            return server_object;
+        }
+        let index = self.next_index;
+        self.next_index = index
+            .checked_add(1)
+            .expect("Ran out of server object indices");
+        ServerObject::new(index, 0)
+    }
+
+    /// Marks the id of a despawned object for reuse once `clients`, those it was replicated to,
+    /// acknowledged the despawn.
+    pub fn free(
+        &mut self,
+        server_object: ServerObject,
+        clients: impl IntoIterator<Item = ClientId>,
+    ) {
+        self.despawned
+            .insert(server_object, clients.into_iter().collect());
+        self.recycle(server_object);
+    }
+
+    /// Whether `client_id` is yet to acknowledge the despawn of `server_object`.
+    pub fn awaits_ack(&self, client_id: &ClientId, server_object: &ServerObject) -> bool {
+        self.despawned
+            .get(server_object)
+            .is_some_and(|clients| clients.contains(client_id))
+    }
+
+    pub fn acknowledge(&mut self, client_id: &ClientId, server_object: ServerObject) {
+        if let Some(clients) = self.despawned.get_mut(&server_object) {
+            clients.remove(client_id);
+            self.recycle(server_object);
+        }
+    }
+
+    /// A client that left no longer acknowledges despawns.
+    pub fn forget(&mut self, client_id: &ClientId) {
+        let server_objects = self
+            .despawned
+            .iter_mut()
+            .filter_map(|(server_object, clients)| {
+                clients.remove(client_id).then_some(*server_object)
+            })
+            .collect::<Vec<_>>();
+        for server_object in server_objects {
+            self.recycle(server_object);
+        }
+    }
+
+    /// Makes the id available again if every client acknowledged its despawn.
+    fn recycle(&mut self, server_object: ServerObject) {
+        if !self
+            .despawned
+            .get(&server_object)
+            .is_some_and(HashSet::is_empty)
+        {
+            return;
+        }
+        self.despawned.remove(&server_object);
+        self.free.push_back(ServerObject::new(
+            server_object.index(),
+            server_object.generation().wrapping_add(1),
+        ));
+    }
+}
+
+/// Hands despawn acks, received on the reliable channel after the `ObjectLeft` they answer, to
+/// the instance of the client.
+pub fn handle_despawn_acks(
+    mut despawn_acks: EventReader<FromClient<DespawnAck>>,
+    mut instances: ResMut<Instances>,
+) {
+    for FromClient {
+        client_id,
+        message: DespawnAck(server_object),
+    } in despawn_acks.read()
+    {
+        if let Some(world) = instances.client_world(client_id) {
+            world
+                .resource_mut::<ServerObjects>()
+                .acknowledge(client_id, *server_object);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn freed_ids_are_reused_with_the_next_generation_once_acked() {
+        let mut server_objects = ServerObjects::default();
+        let first = server_objects.allocate();
+        let second = server_objects.allocate();
+        assert_eq!(first, ServerObject::new(0, 0));
+        assert_eq!(second, ServerObject::new(1, 0));
+
+        let clients = [ClientId::from_raw(1), ClientId::from_raw(2)];
+        server_objects.free(first, clients);
+        assert!(server_objects.awaits_ack(&clients[0], &first));
+        assert!(!server_objects.awaits_ack(&clients[0], &second));
+        server_objects.acknowledge(&clients[0], first);
+        // A repeated ack does not stand in for another client's.
+        server_objects.acknowledge(&clients[0], first);
+        assert_eq!(server_objects.allocate(), ServerObject::new(2, 0));
+
+        server_objects.acknowledge(&clients[1], first);
+        assert!(!server_objects.awaits_ack(&clients[1], &first));
+        assert_eq!(server_objects.allocate(), ServerObject::new(0, 1));
+        assert_eq!(server_objects.allocate(), ServerObject::new(3, 0));
+    }
+
+    #[test]
+    fn ids_not_replicated_to_any_client_are_reused_right_away() {
+        let mut server_objects = ServerObjects::default();
+        let server_object = server_objects.allocate();
+        server_objects.free(server_object, []);
+        assert_eq!(server_objects.allocate(), ServerObject::new(0, 1));
+    }
+
+    #[test]
+    fn clients_that_left_are_not_waited_for() {
+        let mut server_objects = ServerObjects::default();
+        let ids = (0..2)
+            .map(|_| server_objects.allocate())
+            .collect::<Vec<_>>();
+        let (staying, leaving) = (ClientId::from_raw(1), ClientId::from_raw(2));
+        server_objects.free(ids[0], [staying, leaving]);
+        server_objects.free(ids[1], [leaving]);
+        server_objects.forget(&leaving);
+        assert_eq!(server_objects.allocate(), ServerObject::new(1, 1));
+        assert_eq!(server_objects.allocate(), ServerObject::new(2, 0));
+
+        server_objects.acknowledge(&staying, ids[0]);
+        assert_eq!(server_objects.allocate(), ServerObject::new(0, 1));
+    }
+}
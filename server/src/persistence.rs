@@ -0,0 +1,95 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use bevy::{prelude::*, utils::HashMap};
+use common::{bundles::PlayerData, PlayerId};
+
+#[derive(Debug)]
+pub enum PersistError {
+    Io {
+        path: PathBuf,
+        err: io::Error,
+    },
+    Parse {
+        path: PathBuf,
+        err: ron::error::SpannedError,
+    },
+    Serialize(ron::Error),
+}
+
+impl std::fmt::Display for PersistError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io { path, err } => write!(f, "Failed to access {}: {}", path.display(), err),
+            Self::Parse { path, err } => write!(f, "Failed to parse {}: {}", path.display(), err),
+            Self::Serialize(err) => write!(f, "Failed to serialize players: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for PersistError {}
+
+/// Players that left the game, restored when they log in again. Saved to
+/// `ServerSettings::state_path` on shutdown along with the players still in the game.
+#[derive(Resource, Default)]
+pub struct PlayerStore {
+    path: Option<PathBuf>,
+    players: HashMap<PlayerId, PlayerData>,
+}
+
+impl PlayerStore {
+    /// Reads the players saved at `path`, if the file exists.
+    pub fn load(path: Option<&Path>) -> Result<Self, PersistError> {
+        let Some(path) = path else {
+            return Ok(Self::default());
+        };
+        let players = match fs::read_to_string(path) {
+            Ok(source) => {
+                ron::from_str::<Vec<PlayerData>>(&source).map_err(|err| PersistError::Parse {
+                    path: path.to_owned(),
+                    err,
+                })?
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => {
+                return Err(PersistError::Io {
+                    path: path.to_owned(),
+                    err,
+                })
+            }
+        };
+        Ok(Self {
+            path: Some(path.to_owned()),
+            players: players
+                .into_iter()
+                .map(|player_data| (player_data.player.id, player_data))
+                .collect(),
+        })
+    }
+
+    pub fn insert(&mut self, player_data: PlayerData) {
+        self.players.insert(player_data.player.id, player_data);
+    }
+
+    pub fn take(&mut self, player_id: &PlayerId) -> Option<PlayerData> {
+        self.players.remove(player_id)
+    }
+
+    /// Writes the stored players to the state file, if there is one.
+    pub fn save(&self) -> Result<(), PersistError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let players = self.players.values().collect::<Vec<_>>();
+        let source = ron::ser::to_string_pretty(&players, Default::default())
+            .map_err(PersistError::Serialize)?;
+        fs::write(path, source).map_err(|err| PersistError::Io {
+            path: path.clone(),
+            err,
+        })?;
+        info!("Saved {} players to {}", players.len(), path.display());
+        Ok(())
+    }
+}
@@ -0,0 +1,153 @@
+use bevy::{prelude::*, utils::HashMap};
+use bevy_renet::renet::ClientId;
+use common::{
+    delta::Delta,
+    quantize::{NetTransform, TRANSFORM_PRECISION},
+    GameSync, ServerObject,
+};
+
+use crate::interest::CELL_SIZE;
+
+/// Bytes of game sync sent to each client per tick at most.
+pub const SYNC_BUDGET_BYTES: usize = 4 * 1024;
+/// Distance from the client at which an object's priority grows half as fast.
+const PRIORITY_FALLOFF_DISTANCE: f32 = CELL_SIZE;
+/// Multiplies the importance of a client's own player.
+const OWN_PLAYER_IMPORTANCE: f32 = 4.0;
+
+/// How quickly an object's sync priority grows, one if missing.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct SyncImportance(pub f32);
+
+impl Default for SyncImportance {
+    fn default() -> Self {
+        Self(1.0)
+    }
+}
+
+/// Priority accumulated by every server object in each client's area of interest since it was
+/// last synced to that client. An object of importance one next to the client gains one per tick.
+#[derive(Resource)]
+pub struct SyncPriorities {
+    priorities: HashMap<ClientId, HashMap<ServerObject, f32>>,
+    /// Priority an object must accumulate before it is synced.
+    threshold: f32,
+}
+
+impl SyncPriorities {
+    /// Objects of importance one next to a client are synced every `interval_frames`.
+    pub fn new(interval_frames: f32) -> Self {
+        Self {
+            priorities: HashMap::default(),
+            threshold: interval_frames,
+        }
+    }
+
+    /// Accumulates the priority of `objects` over a tick and picks the objects to sync, most
+    /// urgent first, whose `size` fits in `budget` bytes. Objects not in `objects` are forgotten.
+    /// The priority of the objects is only reset once they are `synced`.
+    pub fn select(
+        &mut self,
+        client_id: ClientId,
+        own_object: Option<ServerObject>,
+        origin: Vec3,
+        objects: impl Iterator<Item = (ServerObject, Vec3, SyncImportance)>,
+        size: impl Fn(&ServerObject) -> usize,
+        budget: usize,
+    ) -> Vec<ServerObject> {
+        let previous = self.priorities.remove(&client_id).unwrap_or_default();
+        let priorities = objects
+            .map(|(server_obj, translation, SyncImportance(importance))| {
+                let mut importance = importance;
+                if Some(server_obj) == own_object {
+                    importance *= OWN_PLAYER_IMPORTANCE;
+                }
+                let distance = translation.truncate().distance(origin.truncate());
+                let growth = importance / (1.0 + distance / PRIORITY_FALLOFF_DISTANCE);
+                let priority = previous.get(&server_obj).copied().unwrap_or_default() + growth;
+                (server_obj, priority)
+            })
+            .collect::<HashMap<_, _>>();
+
+        let mut candidates = priorities
+            .iter()
+            .filter(|(_, priority)| **priority >= self.threshold)
+            .map(|(server_obj, priority)| (*server_obj, *priority))
+            .collect::<Vec<_>>();
+        candidates.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+
+        let mut remaining = budget;
+        let mut selected = Vec::new();
+        for (server_obj, _) in candidates {
+            let size = size(&server_obj);
+            if size > remaining {
+                continue;
+            }
+            remaining -= size;
+            selected.push(server_obj);
+        }
+
+        self.priorities.insert(client_id, priorities);
+        selected
+    }
+
+    /// Resets the priority of the objects sent to a client.
+    pub fn synced<'a>(
+        &mut self,
+        client_id: &ClientId,
+        objects: impl Iterator<Item = &'a ServerObject>,
+    ) {
+        let Some(priorities) = self.priorities.get_mut(client_id) else {
+            return;
+        };
+        for server_obj in objects {
+            priorities.insert(*server_obj, 0.0);
+        }
+    }
+
+    pub fn remove(&mut self, client_id: &ClientId) {
+        self.priorities.remove(client_id);
+    }
+}
+
+/// Encoded size of a server object's entries in a game sync, or in a delta against `baseline`.
+pub fn object_size(
+    baseline: Option<&GameSync>,
+    game_sync: &GameSync,
+    server_obj: &ServerObject,
+) -> usize {
+    let transform = game_sync.transforms.get(server_obj).map(|transform| {
+        match baseline {
+            Some(baseline) => {
+                let baseline_transform = baseline
+                    .transforms
+                    .get(server_obj)
+                    .copied()
+                    .unwrap_or_default();
+                let delta = transform.diff(&baseline_transform).unwrap_or_default();
+                bincode::serialized_size(&(server_obj, delta))
+            }
+            None => bincode::serialized_size(&(
+                server_obj,
+                NetTransform::quantize(transform, &TRANSFORM_PRECISION),
+            )),
+        }
+        .unwrap()
+    });
+    let player = game_sync.players.get(server_obj).map(|player| {
+        match baseline {
+            Some(baseline) => {
+                let baseline_player = baseline
+                    .players
+                    .get(server_obj)
+                    .copied()
+                    .unwrap_or_default();
+                let delta = player.diff(&baseline_player).unwrap_or_default();
+                bincode::serialized_size(&(server_obj, delta))
+            }
+            None => bincode::serialized_size(&(server_obj, player)),
+        }
+        .unwrap()
+    });
+    (transform.unwrap_or_default() + player.unwrap_or_default()) as usize
+}
@@ -0,0 +1,201 @@
+use std::time::Duration;
+
+use bevy::{prelude::*, utils::HashMap};
+use bevy_renet::renet::transport::generate_random_bytes;
+use common::{schedule::ServerSchedule, InstanceId, PlayerId, SessionToken};
+
+use crate::{
+    instance::{InstancePlayer, Instances},
+    persistence::PlayerStore,
+};
+
+struct Session {
+    token: SessionToken,
+    player: InstancePlayer,
+    /// Time after which the player is despawned, while its client is disconnected.
+    expires_at: Option<Duration>,
+}
+
+/// The player of every client in game, kept for a grace period after its client disconnects
+/// so that it can reconnect and resume playing.
+#[derive(Resource)]
+pub struct Sessions {
+    sessions: HashMap<PlayerId, Session>,
+    grace: Duration,
+}
+
+impl Sessions {
+    pub fn new(grace: Duration) -> Self {
+        Self {
+            sessions: HashMap::default(),
+            grace,
+        }
+    }
+
+    /// Players with a session, whether their client is connected or not.
+    pub fn count(&self) -> usize {
+        self.sessions.len()
+    }
+
+    pub fn contains(&self, player_id: &PlayerId) -> bool {
+        self.sessions.contains_key(player_id)
+    }
+
+    /// Whether any player has a session in the instance.
+    pub fn in_instance(&self, instance: InstanceId) -> bool {
+        self.sessions
+            .values()
+            .any(|session| session.player.instance == instance)
+    }
+
+    /// Whether `token` resumes the session of `player_id`, whose client disconnected.
+    pub fn can_resume(&self, player_id: &PlayerId, token: SessionToken) -> bool {
+        self.sessions
+            .get(player_id)
+            .is_some_and(|session| session.token == token && session.expires_at.is_some())
+    }
+
+    pub fn start(&mut self, player_id: PlayerId, player: InstancePlayer) -> SessionToken {
+        let token = SessionToken(u64::from_le_bytes(generate_random_bytes()));
+        self.sessions.insert(
+            player_id,
+            Session {
+                token,
+                player,
+                expires_at: None,
+            },
+        );
+        token
+    }
+
+    /// Instance and entity of a player with a session.
+    pub fn player(&self, player_id: &PlayerId) -> Option<InstancePlayer> {
+        self.sessions.get(player_id).map(|session| session.player)
+    }
+
+    /// Returns the player's instance and entity, the token must be checked with `can_resume`
+    /// first.
+    pub fn resume(&mut self, player_id: &PlayerId) -> Option<InstancePlayer> {
+        let session = self.sessions.get_mut(player_id)?;
+        session.expires_at = None;
+        Some(session.player)
+    }
+
+    /// Starts the grace period of a player whose client disconnected at `now`.
+    pub fn disconnect(&mut self, player_id: &PlayerId, now: Duration) {
+        let grace = self.grace;
+        if let Some(session) = self.sessions.get_mut(player_id) {
+            session.expires_at.get_or_insert(now + grace);
+        }
+    }
+
+    /// Expires the session of a player at `now`, so that it cannot be resumed.
+    pub fn revoke(&mut self, player_id: &PlayerId, now: Duration) {
+        if let Some(session) = self.sessions.get_mut(player_id) {
+            session.expires_at = Some(now);
+        }
+    }
+
+    /// Ends the session of a player, returning where its player is.
+    pub fn end(&mut self, player_id: &PlayerId) -> Option<InstancePlayer> {
+        self.sessions
+            .remove(player_id)
+            .map(|session| session.player)
+    }
+
+    fn expired(&self, now: Duration) -> Vec<PlayerId> {
+        self.sessions
+            .iter()
+            .filter(|(_, session)| {
+                session
+                    .expires_at
+                    .is_some_and(|expires_at| expires_at <= now)
+            })
+            .map(|(player_id, _)| *player_id)
+            .collect()
+    }
+}
+
+/// Despawns the players of clients that did not reconnect in time, storing them for their
+/// next login.
+fn expire_sessions(
+    time: Res<Time>,
+    mut sessions: ResMut<Sessions>,
+    mut instances: ResMut<Instances>,
+    mut store: ResMut<PlayerStore>,
+) {
+    for player_id in sessions.expired(time.elapsed()) {
+        info!("Session of player {} expired", player_id);
+        let Some(player) = sessions.end(&player_id) else {
+            continue;
+        };
+        if let Some(player_data) = instances.despawn_player(player) {
+            store.insert(player_data);
+        }
+    }
+}
+
+pub struct SessionPlugin {
+    pub grace: Duration,
+}
+
+impl Plugin for SessionPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(Sessions::new(self.grace)).add_systems(
+            FixedUpdate,
+            expire_sessions.in_set(ServerSchedule::Connections),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PLAYER: PlayerId = PlayerId(1);
+    const GRACE: Duration = Duration::from_secs(30);
+
+    fn player() -> InstancePlayer {
+        InstancePlayer {
+            instance: InstanceId(0),
+            entity: Entity::from_raw(7),
+            server_object: common::ServerObject::new(3, 0),
+        }
+    }
+
+    #[test]
+    fn disconnected_session_resumes_with_its_token() {
+        let mut sessions = Sessions::new(GRACE);
+        let token = sessions.start(PLAYER, player());
+        assert!(!sessions.can_resume(&PLAYER, token));
+
+        sessions.disconnect(&PLAYER, Duration::from_secs(10));
+        assert!(!sessions.can_resume(&PLAYER, SessionToken(token.0.wrapping_add(1))));
+        assert!(sessions.can_resume(&PLAYER, token));
+        let resumed = sessions.resume(&PLAYER).unwrap();
+        assert_eq!(resumed.entity, player().entity);
+        assert!(sessions.expired(Duration::from_secs(100)).is_empty());
+    }
+
+    #[test]
+    fn session_expires_after_the_grace_period() {
+        let mut sessions = Sessions::new(GRACE);
+        sessions.start(PLAYER, player());
+        sessions.disconnect(&PLAYER, Duration::from_secs(10));
+        // Disconnecting again does not extend the grace period.
+        sessions.disconnect(&PLAYER, Duration::from_secs(20));
+
+        assert!(sessions.expired(Duration::from_secs(39)).is_empty());
+        assert_eq!(sessions.expired(Duration::from_secs(40)), vec![PLAYER]);
+        assert!(sessions.end(&PLAYER).is_some());
+        assert!(!sessions.contains(&PLAYER));
+    }
+
+    #[test]
+    fn revoked_session_expires_right_away() {
+        let mut sessions = Sessions::new(GRACE);
+        sessions.start(PLAYER, player());
+        sessions.revoke(&PLAYER, Duration::from_secs(10));
+        assert_eq!(sessions.expired(Duration::from_secs(10)), vec![PLAYER]);
+    }
+}
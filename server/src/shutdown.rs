@@ -0,0 +1,96 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use bevy::{app::AppExit, prelude::*};
+use common::{channel::NetServer, schedule::ServerSchedule, ROMFromServer, ShutdownNotice};
+
+use crate::{instance::Instances, persistence::PlayerStore};
+
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Requests a shutdown on SIGINT or SIGTERM. A second signal exits at once.
+#[cfg(unix)]
+fn install_signal_handlers() {
+    extern "C" fn request_shutdown(_signal: libc::c_int) {
+        if SHUTDOWN_REQUESTED.swap(true, Ordering::SeqCst) {
+            // Only async-signal-safe functions may be called here.
+            unsafe { libc::_exit(1) };
+        }
+    }
+
+    let handler = request_shutdown as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+}
+
+#[cfg(not(unix))]
+fn install_signal_handlers() {}
+
+/// Counts down to the shutdown once it is requested.
+#[derive(Resource)]
+struct Shutdown {
+    countdown: Timer,
+    /// Seconds left in the last notice sent to clients.
+    last_notice: Option<u32>,
+}
+
+/// Warns clients every second of the countdown, then saves the players of every instance and
+/// exits, which disconnects every client.
+fn shutdown(
+    time: Res<Time>,
+    mut shutdown: ResMut<Shutdown>,
+    mut server: NetServer,
+    mut store: ResMut<PlayerStore>,
+    mut instances: ResMut<Instances>,
+    mut exit: EventWriter<AppExit>,
+) {
+    if !SHUTDOWN_REQUESTED.load(Ordering::SeqCst) {
+        return;
+    }
+    if shutdown.last_notice.is_none() {
+        info!(
+            "Shutting down in {} seconds",
+            shutdown.countdown.duration().as_secs_f32()
+        );
+    } else {
+        shutdown.countdown.tick(time.delta());
+    }
+
+    let seconds_left = shutdown.countdown.remaining_secs().ceil() as u32;
+    if shutdown.last_notice != Some(seconds_left) {
+        shutdown.last_notice = Some(seconds_left);
+        server.broadcast(ROMFromServer::ShutdownNotice(ShutdownNotice {
+            seconds_left,
+        }));
+    }
+    if !shutdown.countdown.finished() {
+        return;
+    }
+
+    for (_, instance) in instances.iter_mut() {
+        for player_data in instance.player_data() {
+            store.insert(player_data);
+        }
+    }
+    if let Err(err) = store.save() {
+        error!("{}", err);
+    }
+    info!("Shutting down");
+    exit.send(AppExit);
+}
+
+pub struct ShutdownPlugin {
+    pub countdown_seconds: f32,
+}
+
+impl Plugin for ShutdownPlugin {
+    fn build(&self, app: &mut App) {
+        install_signal_handlers();
+        app.insert_resource(Shutdown {
+            countdown: Timer::from_seconds(self.countdown_seconds, TimerMode::Once),
+            last_notice: None,
+        })
+        .add_systems(FixedUpdate, shutdown.in_set(ServerSchedule::Connections));
+    }
+}
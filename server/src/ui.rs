@@ -1,6 +1,9 @@
 use bevy::{prelude::*, utils::HashMap};
-use common::{rollback::SyncFrameCount, Player, PlayerId};
+use common::{conditioner::LinkConditioner, PlayerId};
 
+use crate::instance::Instances;
+
+/// Inputs received from each player of an instance.
 #[derive(Resource, Default)]
 pub struct InputTracker {
     pub inputs: HashMap<PlayerId, u64>,
@@ -23,14 +26,21 @@ pub fn setup_ui(mut commands: Commands) {
         })
         .with_children(|parent| {
             parent
-                .spawn(SyncFrameCounter)
+                .spawn(LinkConditionsText)
                 .insert(TextBundle::from_section(
-                    "Frame: -",
+                    "",
                     TextStyle {
                         font_size: 20.0,
                         ..Default::default()
                     },
                 ));
+            parent.spawn(InstancesText).insert(TextBundle::from_section(
+                "No instances",
+                TextStyle {
+                    font_size: 20.0,
+                    ..Default::default()
+                },
+            ));
         });
     commands.spawn(Camera2dBundle::default());
 }
@@ -39,83 +49,62 @@ pub struct UIPlugin;
 
 impl Plugin for UIPlugin {
     fn build(&self, app: &mut App) {
-        app.init_resource::<InputTracker>()
-            .add_systems(Startup, setup_ui)
-            .add_systems(
-                FixedUpdate,
-                (
-                    spawn_input_counters,
-                    update_input_counters,
-                    update_frame_counter,
-                    delete_input_counters,
-                )
-                    .chain(),
-            );
+        app.add_systems(Startup, setup_ui)
+            .add_systems(Update, adjust_link_conditions)
+            .add_systems(FixedUpdate, update_instances_text);
     }
 }
 
+/// Frame of every instance and inputs received from each of its players.
 #[derive(Component)]
-pub struct SyncFrameCounter;
+pub struct InstancesText;
 
-#[derive(Component)]
-pub struct InputCounter {
-    pub player_id: PlayerId,
-}
-
-fn spawn_input_counters(
-    mut commands: Commands,
-    ui: Query<Entity, With<UIRoot>>,
-    player_q: Query<&Player, Added<Player>>,
+fn update_instances_text(
+    instances: Res<Instances>,
+    mut text_q: Query<&mut Text, With<InstancesText>>,
 ) {
-    let ui_entity = ui.single();
-    for player in player_q.iter() {
-        commands.entity(ui_entity).with_children(|parent| {
-            parent
-                .spawn(InputCounter {
-                    player_id: player.id,
-                })
-                .insert(TextBundle::from_section(
-                    "",
-                    TextStyle {
-                        font_size: 20.0,
-                        ..Default::default()
-                    },
-                ));
-        });
-    }
-}
-
-fn delete_input_counters(
-    mut commands: Commands,
-    counter_q: Query<(Entity, &InputCounter)>,
-    player_q: Query<&Player>,
-) {
-    for (entity, counter) in counter_q.iter() {
-        if !player_q.iter().any(|p| p.id == counter.player_id) {
-            commands.entity(entity).despawn_recursive();
+    let mut lines = Vec::new();
+    for (id, instance) in instances.iter() {
+        lines.push(format!(
+            "Instance {} ({}): frame {}",
+            id,
+            instance.name(),
+            instance.frame()
+        ));
+        let mut inputs = instance
+            .world()
+            .resource::<InputTracker>()
+            .inputs
+            .iter()
+            .collect::<Vec<_>>();
+        inputs.sort_by_key(|(player_id, _)| player_id.0);
+        for (player_id, count) in inputs {
+            lines.push(format!("  Player {}: {}", player_id.0, count));
         }
     }
-}
-
-fn update_input_counters(
-    input_tracker: Res<InputTracker>,
-    mut text_q: Query<(&mut Text, &InputCounter)>,
-) {
-    for (mut text, input_counter) in text_q.iter_mut() {
-        let count = input_tracker
-            .inputs
-            .get(&input_counter.player_id)
-            .copied()
-            .unwrap_or(0);
-        text.sections[0].value = format!("Player {}: {}", input_counter.player_id.0, count);
+    if lines.is_empty() {
+        lines.push("No instances".to_string());
+    }
+    for mut text in text_q.iter_mut() {
+        text.sections[0].value = lines.join("\n");
     }
 }
 
-fn update_frame_counter(
-    sync_frame_count: Res<SyncFrameCount>,
-    mut text_q: Query<(&mut Text, &SyncFrameCounter)>,
+/// Default conditions of messages from clients, changed with the keys of
+/// `LinkConditions::adjust`.
+#[derive(Component)]
+pub struct LinkConditionsText;
+
+fn adjust_link_conditions(
+    keys: Res<Input<KeyCode>>,
+    mut conditioner: ResMut<LinkConditioner>,
+    mut text_q: Query<&mut Text, With<LinkConditionsText>>,
 ) {
-    for (mut text, _) in text_q.iter_mut() {
-        text.sections[0].value = format!("Frame: {}", sync_frame_count.count());
+    let conditions = &mut conditioner.client_to_server.default;
+    if conditions.adjust(&keys) {
+        info!("Conditions of messages from clients: {}", conditions);
+    }
+    for mut text in text_q.iter_mut() {
+        text.sections[0].value = format!("From clients (F1-F10): {}", conditions);
     }
 }
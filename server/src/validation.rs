@@ -0,0 +1,215 @@
+use bevy::{prelude::*, utils::HashMap};
+use bevy_renet::renet::{ClientId, RenetServer};
+use common::{
+    rollback::{ComponentRollbacks, SyncFrameCount},
+    schedule::ServerSchedule,
+    Player, RawPlayerInput,
+};
+
+use crate::Clients;
+
+/// Strikes after which a client is kicked.
+const MAX_STRIKES: f32 = 10.0;
+/// Seconds after which a strike is forgiven, so that only sustained violations get a client
+/// kicked.
+const STRIKE_DECAY_SECONDS: f32 = 10.0;
+/// Input messages a client may send per tick on average. Clients send one per tick.
+const INPUT_MESSAGES_PER_TICK: f32 = 2.0;
+/// Input messages a client may send at once after a quiet period, to absorb network bursts.
+const INPUT_MESSAGE_BURST: f32 = 10.0;
+/// Slack on the distance a player may move in a frame, for float error.
+const MOVEMENT_TOLERANCE: f32 = 0.01;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    /// A movement axis outside -1 to 1, clamped.
+    AxisOutOfRange,
+    /// A non-finite aim angle, the input is dropped.
+    InvalidAim,
+    /// More input messages than the rate limit allows, the message is dropped.
+    InputFlood,
+    /// A player moved further in a frame than its speed allows.
+    ImplausibleMovement,
+}
+
+impl std::fmt::Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::AxisOutOfRange => write!(f, "movement axis out of range"),
+            Self::InvalidAim => write!(f, "invalid aim"),
+            Self::InputFlood => write!(f, "too many input messages"),
+            Self::ImplausibleMovement => write!(f, "implausible movement"),
+        }
+    }
+}
+
+impl Violation {
+    pub fn rejects_input(&self) -> bool {
+        matches!(self, Self::InvalidAim | Self::InputFlood)
+    }
+}
+
+/// Clamps movement axes to -1 to 1. Returns the first violation found, the input must be
+/// dropped if it `rejects_input`.
+pub fn sanitize_input(raw: &mut RawPlayerInput) -> Result<(), Violation> {
+    if !raw.x_aim.is_finite() {
+        return Err(Violation::InvalidAim);
+    }
+    let (x_move, y_move) = (raw.x_move.clamp(-1, 1), raw.y_move.clamp(-1, 1));
+    if (x_move, y_move) != (raw.x_move, raw.y_move) {
+        raw.x_move = x_move;
+        raw.y_move = y_move;
+        return Err(Violation::AxisOutOfRange);
+    }
+    Ok(())
+}
+
+/// Recent violations of each client, which is kicked once it reaches `MAX_STRIKES`. Strikes
+/// wear off over `STRIKE_DECAY_SECONDS` each.
+#[derive(Resource, Default)]
+pub struct Strikes(HashMap<ClientId, f32>);
+
+impl Strikes {
+    pub fn add(&mut self, client_id: ClientId, violation: Violation) {
+        let strikes = self.0.entry(client_id).or_default();
+        *strikes += 1.0;
+        warn!(
+            "Client {} violated input rules: {} ({:.1}/{} strikes)",
+            client_id, violation, strikes, MAX_STRIKES
+        );
+    }
+
+    /// Forgives strikes for the time elapsed, forgetting clients without any left.
+    fn decay(&mut self, seconds: f32) {
+        for strikes in self.0.values_mut() {
+            *strikes -= seconds / STRIKE_DECAY_SECONDS;
+        }
+        self.0.retain(|_, strikes| *strikes > 0.0);
+    }
+}
+
+/// Token bucket of input messages for each client.
+#[derive(Resource, Default)]
+pub struct InputRateLimiter(HashMap<ClientId, f32>);
+
+impl InputRateLimiter {
+    /// Refills every bucket, once per tick.
+    pub fn tick(&mut self) {
+        for tokens in self.0.values_mut() {
+            *tokens = (*tokens + INPUT_MESSAGES_PER_TICK).min(INPUT_MESSAGE_BURST);
+        }
+    }
+
+    /// Takes a token for an input message, returns `false` if the client is over its rate.
+    pub fn allow(&mut self, client_id: ClientId) -> bool {
+        let tokens = self.0.entry(client_id).or_insert(INPUT_MESSAGE_BURST);
+        if *tokens < 1.0 {
+            return false;
+        }
+        *tokens -= 1.0;
+        true
+    }
+}
+
+/// Checks that every player moved at most its speed on the last frame. Movement is simulated
+/// from clamped inputs, so this guards against anything else moving players.
+fn check_movement(
+    clients: Res<Clients>,
+    mut strikes: ResMut<Strikes>,
+    component_rollbacks: Res<ComponentRollbacks>,
+    frame_count: Res<SyncFrameCount>,
+    time: Res<Time<Fixed>>,
+    player_q: Query<(Entity, &Player)>,
+) {
+    let Some(transforms) = component_rollbacks.get::<Transform>() else {
+        return;
+    };
+    let frame = frame_count.count();
+    if frame == 0 {
+        return;
+    }
+
+    for (entity, player) in player_q.iter() {
+        let (Some(previous), Some(current)) = (
+            transforms.get_value_at_frame(&entity, frame - 1),
+            transforms.get_value_at_frame(&entity, frame),
+        ) else {
+            continue;
+        };
+        let max_distance = player.speed * time.timestep().as_secs_f32() * std::f32::consts::SQRT_2;
+        let distance = previous
+            .translation
+            .truncate()
+            .distance(current.translation.truncate());
+        if distance <= max_distance * (1.0 + MOVEMENT_TOLERANCE) {
+            continue;
+        }
+        let Some(client_id) = clients
+            .players
+            .iter()
+            .find_map(|(client_id, player_id)| (*player_id == player.id).then_some(*client_id))
+        else {
+            continue;
+        };
+        strikes.add(client_id, Violation::ImplausibleMovement);
+    }
+}
+
+/// Kicks clients with too many strikes and forgets clients that left the instance. The
+/// sessions of kicked clients are revoked once they are disconnected.
+fn kick_offenders(
+    time: Res<Time<Fixed>>,
+    mut server: ResMut<RenetServer>,
+    mut strikes: ResMut<Strikes>,
+    mut rate_limiter: ResMut<InputRateLimiter>,
+    clients: Res<Clients>,
+) {
+    strikes.decay(time.delta_seconds());
+    strikes
+        .0
+        .retain(|client_id, _| clients.players.contains_key(client_id));
+    rate_limiter
+        .0
+        .retain(|client_id, _| clients.players.contains_key(client_id));
+
+    for (client_id, strikes) in strikes.0.iter() {
+        if *strikes >= MAX_STRIKES {
+            warn!("Kicking client {} after {:.1} strikes", client_id, strikes);
+            server.disconnect(*client_id);
+        }
+    }
+}
+
+pub struct ValidationPlugin;
+
+impl Plugin for ValidationPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Strikes>()
+            .init_resource::<InputRateLimiter>()
+            .add_systems(
+                FixedUpdate,
+                (
+                    kick_offenders.in_set(ServerSchedule::Connections),
+                    check_movement.in_set(ServerSchedule::HitDetection),
+                ),
+            );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strikes_wear_off() {
+        let client_id = ClientId::from_raw(1);
+        let mut strikes = Strikes::default();
+        strikes.add(client_id, Violation::InputFlood);
+        strikes.add(client_id, Violation::InputFlood);
+
+        strikes.decay(STRIKE_DECAY_SECONDS);
+        assert_eq!(strikes.0.get(&client_id), Some(&1.0));
+        strikes.decay(STRIKE_DECAY_SECONDS);
+        assert!(strikes.0.is_empty());
+    }
+}